@@ -0,0 +1,284 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write};
+use std::iter;
+use std::net::TcpStream;
+use std::path::Path;
+
+/// Size of the buffers files are streamed with.
+pub const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Pieces of a body produced on the fly.
+pub type Chunks = Box<dyn Iterator<Item = IoResult<Vec<u8>>> + Send>;
+
+/// The content of a response. Only [`Body::Bytes`] is held in memory, the others are produced
+/// while being written, so a file costs [`BUFFER_SIZE`] bytes of memory at most, whatever its
+/// size.
+pub enum Body {
+    /// Bytes already in memory.
+    Bytes(Vec<u8>),
+    /// `len` bytes of an open file, starting at `offset`. Sent with `sendfile` where possible.
+    File { file: File, offset: u64, len: u64 },
+    /// Pieces generated one after the other. Their total size is not known in advance.
+    Chunks(Chunks),
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            Body::File { offset, len, .. } => f
+                .debug_struct("File")
+                .field("offset", offset)
+                .field("len", len)
+                .finish_non_exhaustive(),
+            Body::Chunks(_) => f.write_str("Chunks"),
+        }
+    }
+}
+
+/// Bodies in memory are compared by content. Streamed bodies are never equal, as comparing them
+/// would consume them.
+impl PartialEq for Body {
+    fn eq(&self, other: &Body) -> bool {
+        match (self, other) {
+            (Body::Bytes(a), Body::Bytes(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Body {
+    /// Opens the whole file at `path`.
+    pub fn file(path: impl AsRef<Path>) -> IoResult<Body> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Body::File {
+            file,
+            offset: 0,
+            len,
+        })
+    }
+
+    /// Opens `len` bytes of the file at `path`, starting at `offset`.
+    pub fn file_range(path: impl AsRef<Path>, offset: u64, len: u64) -> IoResult<Body> {
+        Ok(Body::File {
+            file: File::open(path)?,
+            offset,
+            len,
+        })
+    }
+
+    /// Number of bytes of the body, if known in advance.
+    pub fn size(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File { len, .. } => Some(*len),
+            Body::Chunks(_) => None,
+        }
+    }
+
+    /// Turns the body into pieces of at most [`BUFFER_SIZE`] bytes, files included.
+    pub fn into_chunks(self) -> Chunks {
+        match self {
+            Body::Bytes(bytes) => Box::new(iter::once(Ok(bytes))),
+            Body::File {
+                mut file,
+                offset,
+                len,
+            } => {
+                if let Err(err) = file.seek(SeekFrom::Start(offset)) {
+                    return Box::new(iter::once(Err(err)));
+                }
+                let mut remaining = len;
+                Box::new(iter::from_fn(move || {
+                    if remaining == 0 {
+                        return None;
+                    }
+                    let mut buffer = vec![0; remaining.min(BUFFER_SIZE as u64) as usize];
+                    match file.read(&mut buffer) {
+                        // the file shrank since its size was read
+                        Ok(0) => {
+                            remaining = 0;
+                            Some(Err(ErrorKind::UnexpectedEof.into()))
+                        }
+                        Ok(n) => {
+                            remaining -= n as u64;
+                            buffer.truncate(n);
+                            Some(Ok(buffer))
+                        }
+                        Err(err) => {
+                            remaining = 0;
+                            Some(Err(err))
+                        }
+                    }
+                }))
+            }
+            Body::Chunks(chunks) => chunks,
+        }
+    }
+
+    /// Writes the whole body to `out`, streaming files through a buffer.
+    pub fn write_to(self, out: &mut impl Write) -> IoResult<()> {
+        if let Body::Bytes(bytes) = self {
+            return out.write_all(&bytes);
+        }
+        for chunk in self.into_chunks() {
+            out.write_all(&chunk?)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the whole body to `stream`. On Linux, files are copied by the kernel with
+    /// `sendfile`, without going through user space.
+    pub fn send_to(self, mut stream: &TcpStream) -> IoResult<()> {
+        match self {
+            Body::File { file, offset, len } => {
+                let sent = sendfile::send(&file, stream, offset, len)?;
+                if sent == len {
+                    return Ok(());
+                }
+                // the kernel can't send this file, it goes through user space then
+                let rest = Body::File {
+                    file,
+                    offset: offset + sent,
+                    len: len - sent,
+                };
+                rest.write_to(&mut stream)
+            }
+            body => body.write_to(&mut stream),
+        }
+    }
+
+    /// Reads the whole body into memory.
+    pub fn into_bytes(self) -> IoResult<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+mod sendfile {
+    use super::IoError;
+    use std::fs::File;
+    use std::io::{ErrorKind, Result as IoResult};
+    use std::net::TcpStream;
+    use std::os::raw::c_int;
+    use std::os::unix::io::AsRawFd;
+
+    /// Largest count a single call may transfer.
+    const MAX_COUNT: u64 = 0x7fff_f000;
+
+    extern "C" {
+        fn sendfile(out_fd: c_int, in_fd: c_int, offset: *mut i64, count: usize) -> isize;
+    }
+
+    /// Errors of `sendfile` for files it can't send, to copy in user space instead. Their values
+    /// are the ones of Linux.
+    #[cfg(target_os = "linux")]
+    const EINVAL: i32 = 22;
+    #[cfg(target_os = "linux")]
+    const ENOSYS: i32 = 38;
+
+    /// Sends `len` bytes of `file` from `offset`. Returns how many were sent before the kernel
+    /// refused the file, which only happens on the first call for files it can't map.
+    pub fn send(file: &File, stream: &TcpStream, offset: u64, len: u64) -> IoResult<u64> {
+        let mut position = offset as i64;
+        let mut sent = 0;
+        while sent < len {
+            let count = (len - sent).min(MAX_COUNT) as usize;
+            let n = unsafe { sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut position, count) };
+            if n > 0 {
+                sent += n as u64;
+                continue;
+            }
+            if n == 0 {
+                // the file shrank since its size was read
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            let err = IoError::last_os_error();
+            match err.kind() {
+                ErrorKind::Interrupted => continue,
+                // not supported for this file
+                _ if sent == 0 && matches!(err.raw_os_error(), Some(EINVAL) | Some(ENOSYS)) => {
+                    return Ok(0)
+                }
+                _ => return Err(err),
+            }
+        }
+        Ok(sent)
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+mod sendfile {
+    use std::fs::File;
+    use std::io::Result as IoResult;
+    use std::net::TcpStream;
+
+    pub fn send(_file: &File, _stream: &TcpStream, _offset: u64, _len: u64) -> IoResult<u64> {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn write_file(name: &str, len: usize) -> (String, Vec<u8>) {
+        fs::create_dir_all("target/qst-tests/body").unwrap();
+        let path = format!("target/qst-tests/body/{name}");
+        let content: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &content).unwrap();
+        (path, content)
+    }
+
+    #[test]
+    fn files_are_streamed_in_buffers() {
+        let (path, content) = write_file("streamed.bin", 3 * BUFFER_SIZE + 7);
+        let body = Body::file(&path).unwrap();
+        assert_eq!(Some(content.len() as u64), body.size());
+        let chunks: Vec<_> = body.into_chunks().map(Result::unwrap).collect();
+        assert_eq!(4, chunks.len());
+        assert!(chunks.iter().all(|chunk| chunk.len() <= BUFFER_SIZE));
+        assert_eq!(content, chunks.concat());
+
+        let body = Body::file_range(&path, 10, BUFFER_SIZE as u64).unwrap();
+        assert_eq!(
+            content[10..10 + BUFFER_SIZE].to_vec(),
+            body.into_bytes().unwrap()
+        );
+    }
+
+    #[test]
+    fn shrunk_files_fail_instead_of_sending_less() {
+        let (path, _) = write_file("shrunk.bin", 10);
+        let body = Body::file_range(&path, 5, 10).unwrap();
+        assert_eq!(
+            ErrorKind::UnexpectedEof,
+            body.into_bytes().unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn files_are_sent_over_sockets() {
+        let (path, content) = write_file("sent.bin", 5 * BUFFER_SIZE + 3);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let sender = thread::spawn(move || {
+            Body::file_range(&path, 3, 5 * BUFFER_SIZE as u64)
+                .unwrap()
+                .send_to(&server)
+                .unwrap();
+        });
+        let mut received = Vec::new();
+        (&client).read_to_end(&mut received).unwrap();
+        sender.join().unwrap();
+        assert_eq!(content[3..], received[..]);
+    }
+}
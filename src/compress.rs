@@ -0,0 +1,442 @@
+/// Files smaller than this are sent as they are, compressing them would save next to nothing.
+pub const MIN_COMPRESSED_SIZE: u64 = 1024;
+
+/// Files larger than this are sent as they are, as they are compressed in memory.
+pub const MAX_COMPRESSED_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Encodings the server compresses with, in order of preference.
+pub const ENCODINGS: [Encoding; 2] = [Encoding::Gzip, Encoding::Deflate];
+
+/// Extensions of the precompressed files served in place of the file they are named after, with
+/// their coding, in order of preference.
+pub const SIDECARS: [(&str, &str); 3] = [("br", "br"), ("zst", "zstd"), ("gz", "gzip")];
+
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// How many earlier positions with the same hash are tried before settling for the best match.
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+/// A content coding of RFC 9110, section 8.4.1.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Encoding {
+    Gzip,
+    /// The zlib format, despite the name.
+    Deflate,
+}
+
+impl Encoding {
+    /// Name of the coding in `Accept-Encoding` and `Content-Encoding`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Gzip => gzip(data),
+            Encoding::Deflate => zlib(data),
+        }
+    }
+}
+
+/// Picks the coding to send among `available`, given in order of preference, from the elements
+/// of `Accept-Encoding`: the one with the highest quality, ties going to the server. Codings with
+/// a quality of 0 are refused and `*` stands for the codings not listed. Returns `None` if the
+/// content should be sent as it is.
+///
+/// # Examples
+/// ```
+/// use qst::compress::negotiate;
+/// assert_eq!(Some("gzip"), negotiate(["deflate", "gzip"].into_iter(), &["gzip", "deflate"]));
+/// assert_eq!(Some("deflate"), negotiate(["gzip;q=0.5", "deflate"].into_iter(), &["gzip", "deflate"]));
+/// assert_eq!(None, negotiate(["br"].into_iter(), &["gzip", "deflate"]));
+/// ```
+pub fn negotiate<'a, 'b>(
+    accepted: impl Iterator<Item = &'a str>,
+    available: &[&'b str],
+) -> Option<&'b str> {
+    let mut qualities: Vec<(String, u32)> = Vec::new();
+    for element in accepted {
+        let mut params = element.split(';');
+        let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let mut quality = 1000;
+        for param in params {
+            if let Some((name, value)) = param.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("q") {
+                    quality = parse_quality(value.trim()).unwrap_or(0);
+                }
+            }
+        }
+        qualities.push((coding, quality));
+    }
+    let quality_of = |coding: &str| {
+        qualities
+            .iter()
+            .find(|(name, _)| name == coding)
+            .or_else(|| qualities.iter().find(|(name, _)| name == "*"))
+            .map_or(0, |(_, quality)| *quality)
+    };
+
+    let mut best: Option<(&'b str, u32)> = None;
+    for coding in available {
+        let quality = quality_of(coding);
+        if quality > 0 && best.map_or(true, |(_, best)| quality > best) {
+            best = Some((coding, quality));
+        }
+    }
+    best.map(|(coding, _)| coding)
+}
+
+/// Parses a quality value, in thousandths.
+fn parse_quality(value: &str) -> Option<u32> {
+    let (int, frac) = value.split_once('.').unwrap_or((value, ""));
+    if frac.len() > 3 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let frac: u32 = format!("{frac:0<3}").parse().ok()?;
+    match int {
+        "0" => Some(frac),
+        "1" if frac == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// Writes bits least significant first, as DEFLATE wants them.
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        self.buffer |= u64::from(value) << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which goes most significant bit first.
+    fn write_code(&mut self, code: u32, bits: u32) {
+        let reversed = code.reverse_bits() >> (32 - bits);
+        self.write(reversed, bits);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Base lengths of the length symbols 257 to 285 and their extra bits.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// Base distances of the distance symbols 0 to 29 and their extra bits.
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Writes a literal/length symbol with the fixed Huffman code (RFC 1951, section 3.2.6).
+fn write_symbol(out: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => out.write_code(0x30 + symbol, 8),
+        144..=255 => out.write_code(0x190 + symbol - 144, 9),
+        256..=279 => out.write_code(symbol - 256, 7),
+        _ => out.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(out: &mut BitWriter, length: usize, distance: usize) {
+    let index = LENGTH_BASE.partition_point(|&base| base as usize <= length) - 1;
+    write_symbol(out, 257 + index as u32);
+    out.write(
+        (length - LENGTH_BASE[index] as usize) as u32,
+        u32::from(LENGTH_EXTRA[index]),
+    );
+    let index = DISTANCE_BASE.partition_point(|&base| base as usize <= distance) - 1;
+    out.write_code(index as u32, 5);
+    out.write(
+        (distance - DISTANCE_BASE[index] as usize) as u32,
+        u32::from(DISTANCE_EXTRA[index]),
+    );
+}
+
+fn hash(data: &[u8]) -> usize {
+    let value = u32::from(data[0]) << 16 | u32::from(data[1]) << 8 | u32::from(data[2]);
+    (value.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Compresses `data` into a raw DEFLATE stream (RFC 1951): a single block of LZ77 matches coded
+/// with the fixed Huffman codes. It compresses less than dynamic codes would, but text still
+/// shrinks to a fraction of its size.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter {
+        bytes: Vec::with_capacity(data.len() / 2),
+        buffer: 0,
+        count: 0,
+    };
+    // final block, fixed codes
+    out.write(1, 1);
+    out.write(1, 2);
+
+    // most recent position of each hash, and the previous position with the same hash
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+    let insert = |head: &mut [usize], prev: &mut [usize], position: usize| {
+        if position + MIN_MATCH <= data.len() {
+            let hash = hash(&data[position..]);
+            prev[position % WINDOW_SIZE] = head[hash];
+            head[hash] = position;
+        }
+    };
+
+    let mut position = 0;
+    while position < data.len() {
+        let mut best = (0, 0);
+        if position + MIN_MATCH <= data.len() {
+            let max = (data.len() - position).min(MAX_MATCH);
+            let mut candidate = head[hash(&data[position..])];
+            let mut chain = 0;
+            while candidate != usize::MAX
+                && position - candidate <= WINDOW_SIZE
+                && chain < MAX_CHAIN
+            {
+                let length = data[candidate..]
+                    .iter()
+                    .zip(&data[position..position + max])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best.0 {
+                    best = (length, position - candidate);
+                    if length == max {
+                        break;
+                    }
+                }
+                let previous = prev[candidate % WINDOW_SIZE];
+                // older than the window, the slot was reused
+                if previous == usize::MAX || previous >= candidate {
+                    break;
+                }
+                candidate = previous;
+                chain += 1;
+            }
+        }
+
+        if best.0 >= MIN_MATCH {
+            write_match(&mut out, best.0, best.1);
+            for skipped in position..position + best.0 {
+                insert(&mut head, &mut prev, skipped);
+            }
+            position += best.0;
+        } else {
+            write_symbol(&mut out, u32::from(data[position]));
+            insert(&mut head, &mut prev, position);
+            position += 1;
+        }
+    }
+    write_symbol(&mut out, 256);
+    out.finish()
+}
+
+/// CRC-32 of `data`, as used by gzip.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Adler-32 of `data`, as used by zlib.
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // the sums can't overflow within this many bytes
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    b << 16 | a
+}
+
+/// Compresses `data` in the gzip format (RFC 1952).
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    // no name nor time, unknown OS
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    out.extend(deflate(data));
+    out.extend(crc32(data).to_le_bytes());
+    out.extend((data.len() as u32).to_le_bytes());
+    out
+}
+
+/// Compresses `data` in the zlib format (RFC 1950).
+pub fn zlib(data: &[u8]) -> Vec<u8> {
+    // 32K window, default level, with a valid check
+    let mut out = vec![0x78, 0x9c];
+    out.extend(deflate(data));
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads bits least significant first.
+    struct BitReader<'a> {
+        data: &'a [u8],
+        position: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bit(&mut self) -> u32 {
+            let bit = self.data[self.position / 8] >> (self.position % 8) & 1;
+            self.position += 1;
+            u32::from(bit)
+        }
+
+        fn bits(&mut self, count: u8) -> u32 {
+            (0..count).fold(0, |value, i| value | self.bit() << i)
+        }
+
+        /// Reads a Huffman code of `count` bits, most significant first, on top of `code`.
+        fn code(&mut self, mut code: u32, count: u32) -> u32 {
+            for _ in 0..count {
+                code = code << 1 | self.bit();
+            }
+            code
+        }
+    }
+
+    /// Decodes the fixed-code blocks written by [`deflate`], independently from it.
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut input = BitReader { data, position: 0 };
+        let mut out = Vec::new();
+        loop {
+            let last = input.bit();
+            assert_eq!(1, input.bits(2), "fixed codes");
+            loop {
+                let code = input.code(0, 7);
+                let symbol = if code <= 0x17 {
+                    code + 256
+                } else {
+                    let code = input.code(code, 1);
+                    match code {
+                        0x30..=0xbf => code - 0x30,
+                        0xc0..=0xc7 => code - 0xc0 + 280,
+                        _ => input.code(code, 1) - 0x190 + 144,
+                    }
+                };
+                match symbol {
+                    0..=255 => out.push(symbol as u8),
+                    256 => break,
+                    _ => {
+                        let index = symbol as usize - 257;
+                        let length =
+                            LENGTH_BASE[index] as usize + input.bits(LENGTH_EXTRA[index]) as usize;
+                        let index = input.code(0, 5) as usize;
+                        let distance = DISTANCE_BASE[index] as usize
+                            + input.bits(DISTANCE_EXTRA[index]) as usize;
+                        for _ in 0..length {
+                            out.push(out[out.len() - distance]);
+                        }
+                    }
+                }
+            }
+            if last == 1 {
+                return out;
+            }
+        }
+    }
+
+    fn samples() -> Vec<Vec<u8>> {
+        let text = "function add(a, b) { return a + b; }\n".repeat(500);
+        let pseudo_random: Vec<u8> = (0..70_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_vec(),
+            (0..=255).collect(),
+            text.into_bytes(),
+            pseudo_random,
+        ]
+    }
+
+    #[test]
+    fn deflate_roundtrips() {
+        for sample in samples() {
+            assert_eq!(sample, inflate(&deflate(&sample)));
+        }
+        let text = "function add(a, b) { return a + b; }\n".repeat(500);
+        assert!(deflate(text.as_bytes()).len() < text.len() / 20);
+    }
+
+    #[test]
+    fn wrappers_have_valid_headers_and_checksums() {
+        // well-known check values
+        assert_eq!(0xcbf4_3926, crc32(b"123456789"));
+        assert_eq!(0x091e_01de, adler32(b"123456789"));
+        assert_eq!(1, adler32(b""));
+
+        for sample in samples() {
+            let gzipped = gzip(&sample);
+            assert_eq!([0x1f, 0x8b, 8], gzipped[..3]);
+            let trailer = &gzipped[gzipped.len() - 8..];
+            assert_eq!(crc32(&sample).to_le_bytes(), trailer[..4]);
+            assert_eq!((sample.len() as u32).to_le_bytes(), trailer[4..]);
+            assert_eq!(sample, inflate(&gzipped[10..gzipped.len() - 8]));
+
+            let zlibbed = zlib(&sample);
+            assert_eq!(0, u16::from_be_bytes([zlibbed[0], zlibbed[1]]) % 31);
+            assert_eq!(adler32(&sample).to_be_bytes(), zlibbed[zlibbed.len() - 4..]);
+            assert_eq!(sample, inflate(&zlibbed[2..zlibbed.len() - 4]));
+        }
+    }
+
+    #[test]
+    fn negotiation_follows_qualities() {
+        let available = ["gzip", "deflate"];
+        let negotiate = |accepted: &str| negotiate(accepted.split(','), &available);
+        assert_eq!(Some("gzip"), negotiate("gzip, deflate, br"));
+        assert_eq!(Some("deflate"), negotiate("deflate"));
+        assert_eq!(Some("deflate"), negotiate("gzip;q=0.8, deflate;q=0.9"));
+        assert_eq!(Some("gzip"), negotiate("*"));
+        assert_eq!(Some("deflate"), negotiate("gzip;q=0, *;q=0.1"));
+        assert_eq!(None, negotiate("GZIP; Q=0, deflate;q=0.000"));
+        assert_eq!(None, negotiate("identity"));
+        assert_eq!(None, negotiate(""));
+        assert_eq!(None, negotiate("gzip;q=2"));
+        assert_eq!(Some("gzip"), negotiate("Gzip ; q=1.0"));
+    }
+}
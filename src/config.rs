@@ -1,3 +1,7 @@
+use crate::mime::MimeTable;
+use crate::mount::{self, MountTable, SymlinkPolicy};
+use crate::proxy::{ProxyTable, Upstream};
+
 #[derive(Debug)]
 #[derive(PartialEq)]
 pub struct Config {
@@ -5,8 +9,27 @@ pub struct Config {
     pub addr: String,
     pub max_threads: Option<usize>,
     pub default_file: String,
+    pub index_fallbacks: Vec<String>,
     pub err404_file: Option<String>,
     pub limit_requests: Option<usize>,
+    pub mime_types: MimeTable,
+    pub mounts: MountTable,
+    pub symlinks: SymlinkPolicy,
+    pub spa_prefixes: Vec<String>,
+    pub proxies: ProxyTable,
+    pub keep_alive_timeout: u64,
+    pub keep_alive_max: usize,
+    pub max_body_size: usize,
+    pub shutdown_timeout: u64,
+    pub dir_listing: bool,
+    pub compression: bool,
+    pub live_reload: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
 }
 
 impl Config {
@@ -18,30 +41,150 @@ impl Config {
             addr: String::from("127.0.0.1"),
             max_threads: None,
             default_file: String::from("index.html"),
+            index_fallbacks: vec![String::from("index.htm"), String::from("README.md")],
             err404_file: None,
             limit_requests: None,
+            mime_types: MimeTable::new(),
+            mounts: MountTable::new(),
+            symlinks: SymlinkPolicy::WithinRoot,
+            spa_prefixes: Vec::new(),
+            proxies: ProxyTable::new(),
+            keep_alive_timeout: 5,
+            keep_alive_max: 100,
+            max_body_size: 10 * 1024 * 1024,
+            shutdown_timeout: 5,
+            dir_listing: false,
+            compression: true,
+            live_reload: false,
         }
     }
 
+    /// Sets an option that takes no value. Returns `false` if `arg` is not such an option.
+    fn set_flag(&mut self, arg: &str) -> bool {
+        match arg {
+            "--dir-listing"    | "-d" => self.dir_listing = true,
+            "--no-compression" | "-z" => self.compression = false,
+            "--live-reload"    | "-w" => self.live_reload = true,
+            _ => return false,
+        }
+        true
+    }
+
     fn set_option(&mut self, arg: String, value: String) -> Result<(), String> {
         match &arg[..] {
             "--port"         | "-p" => self.port         = value.to_string(),
             "--addr"         | "-a" => self.addr         = value.to_string(),
             "--default-file" | "-f" => self.default_file = value.to_string(),
             "--err404-file"  | "-e" => self.err404_file  = Some(value.to_string()),
+            "--index-fallback" | "-i" => if !self.index_fallbacks.contains(&value) {
+                self.index_fallbacks.push(value.to_string())
+            },
+            "--root"         | "-r" => self.mounts.set_root(&value),
             "--max-threads"  | "-t" => {
                 match value.to_string().parse::<usize>() {
                     Err(_) => {
                         let msg = format!("{value} is not a valid number!");
                         return Err(msg);
                     },
-                    Ok(n) if n <= 0 => {
+                    Ok(0) => {
                         let msg = format!("{value} is not integer greater then 0!");
                         return Err(msg);
                     },
                     Ok(n) => self.max_threads = Some(n),
                 };
             },
+            "--mime-type" | "-m" => {
+                match value.split_once('=') {
+                    Some((ext, mime)) if !ext.is_empty() && !mime.is_empty() => {
+                        self.mime_types.insert(ext, mime);
+                    },
+                    _ => {
+                        let msg = format!("{value} is not a valid ext=type mapping!");
+                        return Err(msg);
+                    },
+                }
+            },
+            "--mount" | "-M" => {
+                match value.split_once('=') {
+                    Some((prefix, dir)) if prefix.starts_with('/') && !dir.is_empty() => {
+                        self.mounts.insert(prefix, dir);
+                    },
+                    _ => {
+                        let msg = format!("{value} is not a valid /prefix=dir mount!");
+                        return Err(msg);
+                    },
+                }
+            },
+            "--proxy" | "-P" => {
+                let rule = value.split_once('=').and_then(|(prefix, url)| {
+                    Some((prefix, Upstream::parse(url)?)).filter(|_| prefix.starts_with('/'))
+                });
+                match rule {
+                    Some((prefix, upstream)) => self.proxies.insert(prefix, upstream),
+                    None => {
+                        let msg = format!("{value} is not a valid /prefix=http://host:port proxy!");
+                        return Err(msg);
+                    },
+                }
+            },
+            "--spa" | "-S" => {
+                if !value.starts_with('/') {
+                    let msg = format!("{value} is not a valid /prefix!");
+                    return Err(msg);
+                }
+                self.spa_prefixes.push(mount::normalize_prefix(&value));
+            },
+            "--symlinks" | "-L" => {
+                self.symlinks = match &value[..] {
+                    "follow"      => SymlinkPolicy::Follow,
+                    "within-root" => SymlinkPolicy::WithinRoot,
+                    "deny"        => SymlinkPolicy::Deny,
+                    _ => {
+                        let msg = format!("{value} is not one of follow, within-root or deny!");
+                        return Err(msg);
+                    },
+                };
+            },
+            "--keep-alive-timeout" | "-k" => {
+                match value.to_string().parse::<u64>() {
+                    Err(_) => {
+                        let msg = format!("{value} is not a valid number!");
+                        return Err(msg);
+                    },
+                    Ok(n) => self.keep_alive_timeout = n,
+                }
+            },
+            "--keep-alive-max" | "-K" => {
+                match value.to_string().parse::<usize>() {
+                    Err(_) => {
+                        let msg = format!("{value} is not a valid number!");
+                        return Err(msg);
+                    },
+                    Ok(0) => {
+                        let msg = format!("{value} is not integer greater then 0!");
+                        return Err(msg);
+                    },
+                    Ok(n) => self.keep_alive_max = n,
+                }
+            },
+            "--max-body-size" | "-b" => {
+                match value.to_string().parse::<usize>() {
+                    Err(_) => {
+                        let msg = format!("{value} is not a valid number!");
+                        return Err(msg);
+                    },
+                    Ok(n) => self.max_body_size = n,
+                }
+            },
+            "--shutdown-timeout" | "-s" => {
+                match value.to_string().parse::<u64>() {
+                    Err(_) => {
+                        let msg = format!("{value} is not a valid number!");
+                        return Err(msg);
+                    },
+                    Ok(n) => self.shutdown_timeout = n,
+                }
+            },
             "--limit-requests" | "-l" => {
                 match value.to_string().parse::<usize>() {
                     Err(_) => {
@@ -82,8 +225,11 @@ impl Config {
         loop {
             let arg = match args.next() {
                 Some(arg) => arg,
-                None => return Ok(config),
+                None => return config.validated(),
             };
+            if config.set_flag(&arg) {
+                continue;
+            }
             let value = match args.next() {
                 Some(value) => value,
                 None => {
@@ -94,6 +240,18 @@ impl Config {
             config.set_option(arg, value)?;
         }
     }
+
+    /// Checks the options that depend on others, once they are all set: mounts may be given after
+    /// the `--spa` of their prefix.
+    fn validated(self) -> Result<Config, String> {
+        for prefix in &self.spa_prefixes {
+            if !self.mounts.iter().any(|(mount, _)| mount == prefix) {
+                let msg = format!("{prefix} is not the prefix of a mount!");
+                return Err(msg);
+            }
+        }
+        Ok(self)
+    }
 }
 
 #[cfg(test)]
@@ -116,22 +274,121 @@ mod tests {
             String::from("404.html"),
             String::from("--limit-requests"),
             String::from("4"),
+            String::from("--mime-type"),
+            String::from("glb=model/gltf-binary"),
+            String::from("--keep-alive-timeout"),
+            String::from("0"),
+            String::from("--keep-alive-max"),
+            String::from("10"),
+            String::from("--max-body-size"),
+            String::from("1024"),
+            String::from("--shutdown-timeout"),
+            String::from("30"),
+            String::from("--dir-listing"),
+            String::from("--no-compression"),
+            String::from("--live-reload"),
+            String::from("--index-fallback"),
+            String::from("README.md"),
+            String::from("--root"),
+            String::from("dist/"),
+            String::from("--mount"),
+            String::from("/assets=../shared/assets"),
+            String::from("--symlinks"),
+            String::from("deny"),
+            String::from("--spa"),
+            String::from("/"),
+            String::from("-S"),
+            String::from("/assets/"),
+            String::from("--proxy"),
+            String::from("/api=http://127.0.0.1:8080"),
         ];
         let args = vec_args.iter().map(|s| s.to_string());
         let config = match Config::build_from_cmdline(args) {
             Ok(config) => config,
             Err(msg)   => panic!("Tried valid config, got {msg} instead"),
         };
+        let mut mime_types = MimeTable::new();
+        mime_types.insert("glb", "model/gltf-binary");
+        let mut mounts = MountTable::new();
+        mounts.set_root("dist");
+        mounts.insert("/assets", "../shared/assets");
+        let mut proxies = ProxyTable::new();
+        proxies.insert("/api", Upstream::parse("http://127.0.0.1:8080").unwrap());
         assert_eq!(config, Config {
             port: String::from("420"),
             addr: String::from("192.168.0.1"),
             max_threads: Some(8),
             default_file: String::from("home.html"),
+            index_fallbacks: vec![String::from("index.htm"), String::from("README.md")],
             err404_file: Some(String::from("404.html")),
             limit_requests: Some(4),
+            mime_types,
+            mounts,
+            symlinks: SymlinkPolicy::Deny,
+            spa_prefixes: vec![String::new(), String::from("/assets")],
+            proxies,
+            keep_alive_timeout: 0,
+            keep_alive_max: 10,
+            max_body_size: 1024,
+            shutdown_timeout: 30,
+            dir_listing: true,
+            compression: false,
+            live_reload: true,
         });
     }
 
+    #[test]
+    fn config_rejects_invalid_mime_type() {
+        let vec_args = [
+            String::from("qst"),
+            String::from("--mime-type"),
+            String::from("model/gltf-binary"),
+        ];
+        let args = vec_args.iter().map(|s| s.to_string());
+        assert!(Config::build_from_cmdline(args).is_err());
+    }
+
+    #[test]
+    fn config_rejects_invalid_mount() {
+        for mount in ["assets=shared", "/assets=", "/assets"] {
+            let vec_args = [
+                String::from("qst"),
+                String::from("--mount"),
+                String::from(mount),
+            ];
+            let args = vec_args.iter().map(|s| s.to_string());
+            assert!(Config::build_from_cmdline(args).is_err());
+        }
+    }
+
+    #[test]
+    fn config_rejects_spa_without_mount() {
+        let vec_args = [
+            String::from("qst"),
+            String::from("--spa"),
+            String::from("/app"),
+            String::from("--mount"),
+            String::from("/assets=shared"),
+        ];
+        let args = vec_args.iter().map(|s| s.to_string());
+        assert!(Config::build_from_cmdline(args).is_err());
+        let args = vec_args.iter().map(|s| s.replace("assets", "app"));
+        assert!(Config::build_from_cmdline(args).is_ok());
+    }
+
+    #[test]
+    fn config_rejects_invalid_proxy() {
+        for proxy in ["api=http://127.0.0.1:8080", "/api=127.0.0.1:8080", "/api"] {
+            let vec_args = [
+                String::from("qst"),
+                String::from("--proxy"),
+                String::from(proxy),
+            ];
+            let args = vec_args.iter().map(|s| s.to_string());
+            assert!(Config::build_from_cmdline(args).is_err());
+        }
+    }
+
     #[test]
     fn config_sets_default_with_empty() {
         let vec_args: Vec<String> = vec![];
@@ -141,9 +398,14 @@ mod tests {
             Err(msg) => panic!("Tried valid empty config, got {msg} instead"),
         };
         assert_eq!(config, Config::new());
+        assert_eq!(
+            config.index_fallbacks,
+            vec![String::from("index.htm"), String::from("README.md")]
+        );
     }
 
     #[test]
+    #[allow(clippy::useless_vec)]
     fn config_sets_default_with_one_arg() {
         let vec_args = vec![String::from("prog_name")];
         let args = vec_args.iter().map(|s| s.to_string());
@@ -0,0 +1,230 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// A calendar date and time in UTC.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct UtcDateTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    /// Day of the week, 0 being Sunday.
+    pub weekday: u32,
+}
+
+impl UtcDateTime {
+    /// Converts a number of seconds since the Unix epoch.
+    pub fn from_unix(secs: i64) -> UtcDateTime {
+        let days = secs.div_euclid(86400);
+        let secs_of_day = secs.rem_euclid(86400);
+
+        // civil_from_days, from http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        UtcDateTime {
+            year,
+            month,
+            day,
+            hour: (secs_of_day / 3600) as u32,
+            minute: (secs_of_day % 3600 / 60) as u32,
+            second: (secs_of_day % 60) as u32,
+            // the epoch was a Thursday
+            weekday: (days + 4).rem_euclid(7) as u32,
+        }
+    }
+
+    /// Converts a system time, truncating it to the second.
+    pub fn from_system_time(time: SystemTime) -> UtcDateTime {
+        let secs = match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs() as i64,
+            Err(err) => -(err.duration().as_secs_f64().ceil() as i64),
+        };
+        UtcDateTime::from_unix(secs)
+    }
+
+    /// Converts back to a number of seconds since the Unix epoch. The weekday is ignored.
+    pub fn to_unix(&self) -> i64 {
+        // days_from_civil, from the same source as from_unix
+        let year = self.year - i64::from(self.month <= 2);
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let month = i64::from(self.month);
+        let mp = if month > 2 { month - 3 } else { month + 9 };
+        let doy = (153 * mp + 2) / 5 + i64::from(self.day) - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        days * 86400
+            + i64::from(self.hour) * 3600
+            + i64::from(self.minute) * 60
+            + i64::from(self.second)
+    }
+
+    /// Parses an HTTP date (RFC 9110). The preferred format, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`,
+    /// and the obsolete RFC 850 and asctime ones are accepted. The weekday is not checked.
+    ///
+    /// # Examples
+    /// ```
+    /// use qst::date::UtcDateTime;
+    /// let date = UtcDateTime::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+    /// assert_eq!(784111777, date.to_unix());
+    /// assert_eq!(Some(date), UtcDateTime::parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"));
+    /// assert_eq!(Some(date), UtcDateTime::parse_http_date("Sun Nov  6 08:49:37 1994"));
+    /// ```
+    pub fn parse_http_date(text: &str) -> Option<UtcDateTime> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        let (day, month, year, time) = match fields[..] {
+            // IMF-fixdate
+            [_, day, month, year, time, "GMT"] => (day, month, year.parse().ok()?, time),
+            // RFC 850, with a two digit year
+            [_, date, time, "GMT"] => {
+                let mut parts = date.split('-');
+                let (day, month, year) = (parts.next()?, parts.next()?, parts.next()?);
+                if parts.next().is_some() || year.len() != 2 {
+                    return None;
+                }
+                let year: i64 = year.parse().ok()?;
+                // the year is taken as the closest to now, assumed to be in this century
+                let year = if year < 70 { 2000 + year } else { 1900 + year };
+                (day, month, year, time)
+            }
+            // asctime
+            [_, month, day, time, year] => (day, month, year.parse().ok()?, time),
+            _ => return None,
+        };
+
+        let month = MONTHS.iter().position(|name| *name == month)? as u32 + 1;
+        if day.is_empty() || day.len() > 2 || !day.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let day: u32 = day.parse().ok()?;
+        let mut parts = time.split(':');
+        let mut next = || -> Option<u32> {
+            let part = parts.next()?;
+            if part.len() != 2 || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            part.parse().ok()
+        };
+        let (hour, minute, second) = (next()?, next()?, next()?);
+        if parts.next().is_some() {
+            return None;
+        }
+        // a leap second is allowed by the grammar
+        if day == 0 || day > days_in_month(year, month) || hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+        let secs = UtcDateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second: second.min(59),
+            weekday: 0,
+        }
+        .to_unix();
+        Some(UtcDateTime::from_unix(secs))
+    }
+
+    /// Formats as an HTTP date (RFC 9110), e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+    pub fn to_http_date(&self) -> String {
+        format!(
+            "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+            WEEKDAYS[self.weekday as usize],
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    /// Formats as RFC 3339, e.g. `2024-03-01T12:30:00Z`.
+    pub fn to_rfc3339(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn converts_unix_time_to_calendar() {
+        assert_eq!(
+            "1970-01-01T00:00:00Z",
+            UtcDateTime::from_unix(0).to_rfc3339()
+        );
+        assert_eq!(4, UtcDateTime::from_unix(0).weekday);
+
+        // a leap day
+        let date = UtcDateTime::from_unix(951782400);
+        assert_eq!("2000-02-29T00:00:00Z", date.to_rfc3339());
+        assert_eq!(2, date.weekday);
+
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            "2023-11-14T22:13:20Z",
+            UtcDateTime::from_system_time(time).to_rfc3339()
+        );
+        assert_eq!(
+            "1969-12-31T23:59:59Z",
+            UtcDateTime::from_unix(-1).to_rfc3339()
+        );
+    }
+
+    #[test]
+    fn formats_and_parses_http_dates() {
+        let date = UtcDateTime::from_unix(1_700_000_000);
+        assert_eq!("Tue, 14 Nov 2023 22:13:20 GMT", date.to_http_date());
+        assert_eq!(
+            Some(date),
+            UtcDateTime::parse_http_date(&date.to_http_date())
+        );
+        for secs in [-86400 * 365, 0, 951782400, 4_102_444_800] {
+            assert_eq!(secs, UtcDateTime::from_unix(secs).to_unix());
+        }
+
+        for invalid in [
+            "",
+            "Tue, 14 Nov 2023 22:13:20",
+            "Tue, 14 Nov 2023 22:13:20 UTC",
+            "Tue, 31 Nov 2023 22:13:20 GMT",
+            "Tue, 14 Foo 2023 22:13:20 GMT",
+            "Tue, 14 Nov 2023 24:00:00 GMT",
+            "Tue, 14 Nov 2023 22:13 GMT",
+            "Tue, +4 Nov 2023 22:13:20 GMT",
+            "Tuesday, 14-Nov-2023 22:13:20 GMT",
+        ] {
+            assert_eq!(None, UtcDateTime::parse_http_date(invalid), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::error::Error;
+use std::fmt;
+use std::io::Error as IoError;
+
+/// Errors that stop the server. Failures of a single connection never stop it, they are only
+/// logged and counted in the [`ShutdownReport`](crate::shutdown::ShutdownReport).
+#[derive(Debug)]
+pub enum ServerError {
+    /// The listener could not be bound to the address.
+    Bind { addr: String, source: IoError },
+    /// The listener could not be configured after binding.
+    Listener(IoError),
+    /// Accepting a connection failed.
+    Accept(IoError),
+    /// No worker thread could be spawned to answer a connection.
+    Spawn(IoError),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::Bind { addr, source } => write!(f, "Unable to bind to {addr}: {source}"),
+            ServerError::Listener(err) => write!(f, "Unable to set up the listener: {err}"),
+            ServerError::Accept(err) => write!(f, "Connection failed: {err}"),
+            ServerError::Spawn(err) => write!(f, "Unable to spawn new threads: {err}"),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServerError::Bind { source, .. } => Some(source),
+            ServerError::Listener(err) | ServerError::Accept(err) | ServerError::Spawn(err) => {
+                Some(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn server_error_displays_its_cause() {
+        let err = ServerError::Bind {
+            addr: String::from("127.0.0.1:80"),
+            source: IoError::new(ErrorKind::PermissionDenied, "permission denied"),
+        };
+        assert_eq!(
+            "Unable to bind to 127.0.0.1:80: permission denied",
+            err.to_string()
+        );
+        assert_eq!(
+            ErrorKind::PermissionDenied,
+            err.source()
+                .unwrap()
+                .downcast_ref::<IoError>()
+                .unwrap()
+                .kind()
+        );
+    }
+}
@@ -1,48 +1,318 @@
+use crate::body::{Body, Chunks, BUFFER_SIZE};
+use crate::date::UtcDateTime;
+use crate::url;
+use std::collections::HashMap;
+use std::fmt;
 use std::io::Result as IoResult;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Error as IoError, ErrorKind, Read, Write};
+use std::iter;
 use std::net::TcpStream;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, PartialEq)]
+/// The methods of RFC 9110, section 9, and `PATCH` (RFC 5789).
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum HttpMethod {
     GET,
     HEAD,
+    POST,
+    PUT,
+    DELETE,
+    CONNECT,
+    OPTIONS,
+    TRACE,
+    PATCH,
+}
+
+impl HttpMethod {
+    /// The method as written in the request line.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpMethod::GET => "GET",
+            HttpMethod::HEAD => "HEAD",
+            HttpMethod::POST => "POST",
+            HttpMethod::PUT => "PUT",
+            HttpMethod::DELETE => "DELETE",
+            HttpMethod::CONNECT => "CONNECT",
+            HttpMethod::OPTIONS => "OPTIONS",
+            HttpMethod::TRACE => "TRACE",
+            HttpMethod::PATCH => "PATCH",
+        }
+    }
+
+    /// Parses a method name. Names are case-sensitive, so `get` is not `GET`.
+    pub fn parse(method: &str) -> Option<HttpMethod> {
+        match method {
+            "GET" => Some(HttpMethod::GET),
+            "HEAD" => Some(HttpMethod::HEAD),
+            "POST" => Some(HttpMethod::POST),
+            "PUT" => Some(HttpMethod::PUT),
+            "DELETE" => Some(HttpMethod::DELETE),
+            "CONNECT" => Some(HttpMethod::CONNECT),
+            "OPTIONS" => Some(HttpMethod::OPTIONS),
+            "TRACE" => Some(HttpMethod::TRACE),
+            "PATCH" => Some(HttpMethod::PATCH),
+            _ => None,
+        }
+    }
+}
+
+/// Protocol version of a request. Requests without a version in the request line are treated as
+/// HTTP/1.0.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
+}
+
+impl HttpVersion {
+    /// The version as written in the request line.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpVersion::Http10 => "HTTP/1.0",
+            HttpVersion::Http11 => "HTTP/1.1",
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum HttpResponseCode {
     Continue100,
     OK200,
+    PartialContent206,
+    MovedPermanently301,
+    NotModified304,
     BadRequest400,
     Forbbiden403,
     NotFound404,
     MethodNotAllowed405,
+    RequestTimeout408,
+    ContentTooLarge413,
+    RangeNotSatisfiable416,
+    ExpectationFailed417,
     ImATeapot418,
+    RequestHeaderFieldsTooLarge431,
     NotImplemented501,
+    BadGateway502,
+    GatewayTimeout504,
     HttpVersionNotSupported505,
+    /// Any other status, with its reason phrase, as relayed from an upstream by the proxy.
+    Other(u16, String),
 }
 
-impl ToString for HttpResponseCode {
-    fn to_string(&self) -> String {
-        match self {
-            HttpResponseCode::Continue100 => String::from("100 Continue"),
-            HttpResponseCode::OK200 => String::from("200 Ok"),
-            HttpResponseCode::BadRequest400 => String::from("400 Bad Request"),
-            HttpResponseCode::Forbbiden403 => String::from("403 Forbidden"),
-            HttpResponseCode::NotFound404 => String::from("404 Not Found"),
-            HttpResponseCode::MethodNotAllowed405 => String::from("405 Method Not Allowed"),
-            HttpResponseCode::ImATeapot418 => String::from("418 I'm A Teapot"),
-            HttpResponseCode::NotImplemented501 => String::from("501 Not Implemented"),
-            HttpResponseCode::HttpVersionNotSupported505 => {
-                String::from("505 HTTP Version Not Supported")
+impl fmt::Display for HttpResponseCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self {
+            HttpResponseCode::Continue100 => "100 Continue",
+            HttpResponseCode::OK200 => "200 Ok",
+            HttpResponseCode::PartialContent206 => "206 Partial Content",
+            HttpResponseCode::MovedPermanently301 => "301 Moved Permanently",
+            HttpResponseCode::NotModified304 => "304 Not Modified",
+            HttpResponseCode::BadRequest400 => "400 Bad Request",
+            HttpResponseCode::Forbbiden403 => "403 Forbidden",
+            HttpResponseCode::NotFound404 => "404 Not Found",
+            HttpResponseCode::MethodNotAllowed405 => "405 Method Not Allowed",
+            HttpResponseCode::RequestTimeout408 => "408 Request Timeout",
+            HttpResponseCode::ContentTooLarge413 => "413 Content Too Large",
+            HttpResponseCode::RangeNotSatisfiable416 => "416 Range Not Satisfiable",
+            HttpResponseCode::ExpectationFailed417 => "417 Expectation Failed",
+            HttpResponseCode::ImATeapot418 => "418 I'm A Teapot",
+            HttpResponseCode::RequestHeaderFieldsTooLarge431 => {
+                "431 Request Header Fields Too Large"
             }
+            HttpResponseCode::NotImplemented501 => "501 Not Implemented",
+            HttpResponseCode::BadGateway502 => "502 Bad Gateway",
+            HttpResponseCode::GatewayTimeout504 => "504 Gateway Timeout",
+            HttpResponseCode::HttpVersionNotSupported505 => "505 HTTP Version Not Supported",
+            HttpResponseCode::Other(code, reason) => return write!(f, "{code} {reason}"),
+        };
+        f.write_str(status)
+    }
+}
+
+/// Builds a weak entity tag from the size and modification time of a file. Any change to the file
+/// is expected to change either.
+///
+/// # Examples
+/// ```
+/// use qst::http::weak_etag;
+/// use std::time::{Duration, UNIX_EPOCH};
+/// let modified = UNIX_EPOCH + Duration::new(1_700_000_000, 5);
+/// assert_eq!("W/\"2a-6553f100.5\"", weak_etag(42, modified));
+/// ```
+pub fn weak_etag(len: u64, modified: SystemTime) -> String {
+    let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "W/\"{len:x}-{:x}.{:x}\"",
+        since_epoch.as_secs(),
+        since_epoch.subsec_nanos()
+    )
+}
+
+/// Maximum number of header fields accepted in a request.
+pub const MAX_REQUEST_HEADERS: usize = 100;
+
+/// Maximum length of a line framing a chunk of a request body, extensions included.
+const MAX_CHUNK_LINE: u64 = 4096;
+
+/// A collection of HTTP header fields. Names are matched case-insensitively but kept as given for
+/// serialization, and a name may appear multiple times.
+///
+/// # Examples
+/// ```
+/// use qst::http::Headers;
+/// let mut headers = Headers::new();
+/// headers.append("Accept-Encoding", "gzip");
+/// headers.append("accept-encoding", "br");
+/// assert_eq!(Some("gzip"), headers.get("ACCEPT-ENCODING"));
+/// assert_eq!(vec!["gzip", "br"], headers.get_all("accept-encoding").collect::<Vec<_>>());
+/// ```
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    /// Creates an empty collection.
+    pub fn new() -> Headers {
+        Headers { fields: Vec::new() }
+    }
+
+    /// Returns the first value of the field `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| &value[..])
+    }
+
+    /// Returns every value of the field `name`, in the order they were added.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| &value[..])
+    }
+
+    /// Returns every comma-separated element of every value of the field `name`, trimmed. Useful
+    /// for list-based fields such as `Connection` or `Accept-Encoding`.
+    pub fn get_list<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .map(|element| element.trim())
+            .filter(|element| !element.is_empty())
+    }
+
+    /// Whether the field `name` is present.
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Sets the field `name` to `value`, removing any previous values.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Adds a value to the field `name`, keeping the previous values.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    /// Removes every value of the field `name`.
+    pub fn remove(&mut self, name: &str) {
+        self.fields
+            .retain(|(field, _)| !field.eq_ignore_ascii_case(name));
+    }
+
+    /// Iterates over all fields as `(name, value)` pairs, in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(name, value)| (&name[..], &value[..]))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Reads a header section from `lines` up to the blank line ending it, or to the end of
+    /// `lines` (RFC 9112, section 5). Returns Err with a 400 if a line is not a valid field, a 431
+    /// past [`MAX_REQUEST_HEADERS`] fields, or the response to a read error.
+    pub(crate) fn read_section(
+        lines: impl Iterator<Item = IoResult<String>>,
+    ) -> Result<Headers, HttpResponse> {
+        let mut headers = Headers::new();
+        for line in lines {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => return Err(HttpResponse::from_read_error(err)),
+            };
+            if line.is_empty() {
+                break;
+            }
+            // obsolete line folding is not supported
+            if line.starts_with([' ', '\t']) {
+                return Err(HttpResponse::bad_request_400());
+            }
+            match Headers::parse_field_line(&line) {
+                Some((name, value)) => headers.append(name, value),
+                None => return Err(HttpResponse::bad_request_400()),
+            }
+            if headers.len() > MAX_REQUEST_HEADERS {
+                return Err(HttpResponse {
+                    code: HttpResponseCode::RequestHeaderFieldsTooLarge431,
+                    content: None,
+                    content_length: None,
+                    headers: Headers::new(),
+                });
+            }
+        }
+        Ok(headers)
+    }
+
+    /// Writes the fields as lines of a header section. Framing is up to the writer, so those
+    /// fields are never copied, and neither are invalid names. Control characters are never valid
+    /// within a value, they are replaced to avoid request and response splitting.
+    fn write_lines(&self, head: &mut Vec<u8>) {
+        for (name, value) in self.iter() {
+            let framing = name.eq_ignore_ascii_case("Content-Length")
+                || name.eq_ignore_ascii_case("Transfer-Encoding");
+            if framing || !is_token(name) {
+                continue;
+            }
+            let value = value.replace(|c: char| c.is_ascii_control() && c != '\t', " ");
+            head.extend_from_slice(format!("{name}: {}\r\n", value.trim()).as_bytes());
+        }
+    }
+
+    /// Parses a `name: value` field line. Returns `None` if the line is not a valid field.
+    pub(crate) fn parse_field_line(line: &str) -> Option<(&str, &str)> {
+        let (name, value) = line.split_once(':')?;
+        if !is_token(name) {
+            return None;
         }
+        Some((name, value.trim_matches([' ', '\t'])))
     }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct HttpRequest {
     pub method: HttpMethod,
+    /// The request target, as sent.
     pub fetch: String,
+    /// The decoded parameters of the query string.
+    pub query: HashMap<String, String>,
+    pub version: HttpVersion,
+    pub headers: Headers,
+    /// The content of the request, empty until read with [`HttpRequest::read_body`].
+    pub body: Vec<u8>,
+    /// The trailer fields sent after a chunked body. They are kept apart from `headers`, as
+    /// they can't be trusted to mean the same there (RFC 9110, section 6.5.1).
+    pub trailers: Headers,
 }
 
 impl HttpRequest {
@@ -50,62 +320,269 @@ impl HttpRequest {
     where
         F: Iterator<Item = IoResult<String>>,
     {
-        let (method, fetch) = if let Some(Ok(line)) = iter.next() {
-            let mut line_iter = line.split_ascii_whitespace();
-            if let Some(method) = line_iter.next() {
-                if let Some(fetch) = line_iter.next() {
-                    (method.to_string(), fetch.to_string())
+        let (method, fetch, version) = match iter.next() {
+            Some(Ok(line)) => {
+                let mut line_iter = line.split_ascii_whitespace();
+                if let Some(method) = line_iter.next() {
+                    if let Some(fetch) = line_iter.next() {
+                        let version = line_iter.next().map(|version| version.to_string());
+                        (method.to_string(), fetch.to_string(), version)
+                    } else {
+                        return Err(HttpResponse::bad_request_400());
+                    }
                 } else {
                     return Err(HttpResponse::bad_request_400());
                 }
-            } else {
-                return Err(HttpResponse::bad_request_400());
             }
-        } else {
-            return Err(HttpResponse::bad_request_400());
+            Some(Err(err)) => return Err(HttpResponse::from_read_error(err)),
+            None => return Err(HttpResponse::bad_request_400()),
         };
 
-        let method = match &method[..] {
-            "GET" => HttpMethod::GET,
-            "HEAD" => HttpMethod::HEAD,
-            _ => {
+        let version = match version.as_deref() {
+            None | Some("HTTP/1.0") => HttpVersion::Http10,
+            Some("HTTP/1.1") => HttpVersion::Http11,
+            Some(other) if other.starts_with("HTTP/") => {
+                return Err(HttpResponse {
+                    code: HttpResponseCode::HttpVersionNotSupported505,
+                    content: None,
+                    content_length: None,
+                    headers: Headers::new(),
+                })
+            }
+            Some(_) => return Err(HttpResponse::bad_request_400()),
+        };
+
+        let method = match HttpMethod::parse(&method) {
+            Some(method) => method,
+            None => {
                 return Err(HttpResponse {
                     code: HttpResponseCode::NotImplemented501,
                     content: None,
                     content_length: None,
+                    headers: Headers::new(),
                 })
             }
         };
         let fetch = fetch.to_string();
-        Ok(HttpRequest { method, fetch })
+        let query = match url::split_target(&fetch).1 {
+            Some(query) => match url::parse_query(query) {
+                Some(query) => query,
+                None => return Err(HttpResponse::bad_request_400()),
+            },
+            None => HashMap::new(),
+        };
+
+        let headers = Headers::read_section(iter)?;
+
+        Ok(HttpRequest {
+            method,
+            fetch,
+            query,
+            version,
+            headers,
+            body: Vec::new(),
+            trailers: Headers::new(),
+        })
+    }
+
+    /// Answers the `Expect` field of the request on `out`, before its body is read (RFC 9110,
+    /// section 10.1.1). An HTTP/1.1 client sending `100-continue` waits for a `100 Continue`
+    /// before sending the body, which it gets if the body fits in `limit` bytes.
+    ///
+    /// Returns Err with the response to send instead of reading the body: a 413 if it is too
+    /// long, a 417 for any other expectation.
+    pub fn answer_expectation(
+        &self,
+        out: &mut impl Write,
+        limit: usize,
+    ) -> Result<(), HttpResponse> {
+        let error = |code| HttpResponse {
+            code,
+            content: None,
+            content_length: None,
+            headers: Headers::new(),
+        };
+        let expect = match self.headers.get("Expect") {
+            Some(expect) if self.version == HttpVersion::Http11 => expect,
+            // HTTP/1.0 clients don't wait for the interim response
+            _ => return Ok(()),
+        };
+        if !expect.eq_ignore_ascii_case("100-continue") {
+            return Err(error(HttpResponseCode::ExpectationFailed417));
+        }
+        match Framing::of(&self.headers) {
+            Ok(Some(Framing::Length(len))) if len > limit as u64 => {
+                Err(error(HttpResponseCode::ContentTooLarge413))
+            }
+            Ok(Some(_)) => {
+                // a client that left fails the read of the body that follows
+                let _ = out.write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+                Ok(())
+            }
+            // an invalid framing is answered when the body is read
+            Ok(None) | Err(_) => Ok(()),
+        }
+    }
+
+    /// Reads the body of the request from `reader`, the reader the request was parsed from, framed
+    /// by `Transfer-Encoding: chunked` or `Content-Length` (RFC 9112, section 6.3). Requests with
+    /// neither have no body.
+    ///
+    /// Returns Err with the response to send if the framing is invalid, in which case the rest of
+    /// the stream can't be trusted, or with a 413 if the body is longer than `limit` bytes. Other
+    /// transfer codings get a 501.
+    pub fn read_body(
+        &mut self,
+        reader: &mut impl BufRead,
+        limit: usize,
+    ) -> Result<(), HttpResponse> {
+        let error = |code| HttpResponse {
+            code,
+            content: None,
+            content_length: None,
+            headers: Headers::new(),
+        };
+        let len = match Framing::of(&self.headers) {
+            Ok(None) => return Ok(()),
+            Ok(Some(Framing::Chunked)) => {
+                (self.body, self.trailers) = read_chunked(reader, limit)?;
+                return Ok(());
+            }
+            Ok(Some(Framing::Length(len))) => len,
+            Err(code) => return Err(error(code)),
+        };
+        if len > limit as u64 {
+            return Err(error(HttpResponseCode::ContentTooLarge413));
+        }
+        let mut body = Vec::with_capacity(len as usize);
+        match reader.take(len).read_to_end(&mut body) {
+            Ok(read) if read as u64 == len => {
+                self.body = body;
+                Ok(())
+            }
+            Ok(_) => Err(HttpResponse::bad_request_400()),
+            Err(err) => Err(HttpResponse::from_read_error(err)),
+        }
+    }
+
+    /// Writes the request to `out`: the request line, the header fields and the body, framed by a
+    /// `Content-Length` whatever its framing was when read. Methods that expect a body always get
+    /// one, empty or not, and the trailer fields are dropped.
+    pub fn write_to(self, out: &mut impl Write) -> IoResult<()> {
+        let (method, version) = (self.method.as_str(), self.version.as_str());
+        let mut head = format!("{method} {} {version}\r\n", self.fetch).into_bytes();
+        self.headers.write_lines(&mut head);
+        let expects_body = matches!(
+            self.method,
+            HttpMethod::POST | HttpMethod::PUT | HttpMethod::PATCH
+        );
+        if expects_body || !self.body.is_empty() {
+            head.extend_from_slice(format!("Content-Length: {}\r\n", self.body.len()).as_bytes());
+        }
+        head.extend_from_slice(b"\r\n");
+        head.extend_from_slice(&self.body);
+        out.write_all(&head)
+    }
+
+    /// Whether the client wants the connection to persist after this request, based on the
+    /// protocol version and the `Connection` header.
+    pub fn keep_alive(&self) -> bool {
+        let mut connection = self.headers.get_list("Connection");
+        match self.version {
+            HttpVersion::Http11 => !connection.any(|token| token.eq_ignore_ascii_case("close")),
+            HttpVersion::Http10 => connection.any(|token| token.eq_ignore_ascii_case("keep-alive")),
+        }
+    }
+
+    /// Whether the request announces a non-empty body, see [`HttpRequest::read_body`].
+    pub fn has_body(&self) -> bool {
+        self.headers.contains("Transfer-Encoding")
+            || self
+                .headers
+                .get("Content-Length")
+                .is_some_and(|length| length.trim() != "0")
     }
 
+    /// Whether the client already has the representation identified by `etag` and last modified at
+    /// `modified`, so a 304 can be sent instead (RFC 9110, section 13.2.2). `If-None-Match` is
+    /// compared weakly and takes precedence over `If-Modified-Since`, which is ignored if invalid.
+    pub fn is_not_modified(&self, etag: &str, modified: SystemTime) -> bool {
+        // W/"x" and "x" match weakly
+        let opaque = |tag: &str| tag.trim_start_matches("W/").to_string();
+        if self.headers.contains("If-None-Match") {
+            return self
+                .headers
+                .get_list("If-None-Match")
+                .any(|tag| tag == "*" || opaque(tag) == opaque(etag));
+        }
+        match self
+            .headers
+            .get("If-Modified-Since")
+            .and_then(UtcDateTime::parse_http_date)
+        {
+            Some(since) => UtcDateTime::from_system_time(modified).to_unix() <= since.to_unix(),
+            None => false,
+        }
+    }
+
+    /// Whether the `Range` header applies to the representation identified by `etag` and last
+    /// modified at `modified`, according to `If-Range` (RFC 9110, section 13.1.5). Entity tags are
+    /// compared strongly, so weak ones never match, and dates must be exactly the modification
+    /// time. Without `If-Range`, the range always applies.
+    pub fn if_range_matches(&self, etag: &str, modified: SystemTime) -> bool {
+        let condition = match self.headers.get("If-Range") {
+            Some(condition) => condition.trim(),
+            None => return true,
+        };
+        if condition.starts_with('"') || condition.starts_with("W/") {
+            return !etag.starts_with("W/") && condition == etag;
+        }
+        match UtcDateTime::parse_http_date(condition) {
+            Some(date) => UtcDateTime::from_system_time(modified).to_unix() == date.to_unix(),
+            None => false,
+        }
+    }
+
+    /// Parses a single request from `stream`. Anything buffered past the request is dropped, so
+    /// pipelined requests are lost; parse from a long-lived `BufReader` on persistent connections.
     pub fn parse_tcp_stream(stream: &mut TcpStream) -> Result<HttpRequest, HttpResponse> {
         let stream_reader = BufReader::new(stream);
         HttpRequest::parse_from_lines_iterator(stream_reader.lines())
     }
 
-    /// Returns the correct path to fetch based on the fetch from a request. Will always be based
-    /// uppon the current working directory, starting with `./`.
+    /// Returns the correct path to fetch based on the fetch from a request. Will always start with
+    /// `./`; mapping it to a directory is up to the caller, see [`crate::mount::MountTable`].
+    ///
+    /// The query and fragment are ignored and the path is percent-decoded. If it can't be decoded
+    /// to UTF-8, returns Err with a 400 response.
     ///
-    /// If the path is impossible or insecure in a Linux system (i.e., contains "..", "//", or ends
-    /// with "/"), returns Err with a proper response to that.
+    /// If the path is impossible or insecure in a Linux system (i.e., has a `..` segment or an empty
+    /// one, as in `//`), returns Err with a proper response to that. Names merely containing dots,
+    /// like `foo..bar.js`, are fine. This only keeps the path from climbing up lexically: symlinks
+    /// are checked once the path is mapped to the filesystem, see [`crate::mount::Resolved`].
     ///
-    /// If the fetch is `/`, returns `default`.
+    /// Paths ending with `/` name a directory and keep the trailing `/`. Finding the index file of
+    /// the directory is up to the caller.
     ///
     /// # Examples:
     /// ```
     /// use qst::http::*;
-    /// let request = HttpRequest {
+    /// use std::collections::HashMap;
+    /// let mut request = HttpRequest {
     ///     method: HttpMethod::GET,
     ///     fetch: String::from("/"),
+    ///     query: HashMap::new(),
+    ///     version: HttpVersion::Http11,
+    ///     headers: Headers::new(),
+    ///     body: Vec::new(),
+    ///     trailers: Headers::new(),
     /// };
-    /// assert_eq!("./index.html", HttpRequest::match_fetch(&request, "index.html").unwrap());
+    /// assert_eq!("./", HttpRequest::match_fetch(&request).unwrap());
+    /// request.fetch = String::from("/css/style.css");
+    /// assert_eq!("./css/style.css", HttpRequest::match_fetch(&request).unwrap());
     /// ```
-    pub fn match_fetch(&self, default: &str) -> Result<String, HttpResponse> {
-        if self.fetch == "/" {
-            Ok(format!("./{default}"))
-        } else if self.fetch == "//coffee" {
+    pub fn match_fetch(&self) -> Result<String, HttpResponse> {
+        if self.raw_path() == "//coffee" {
             let content = String::from(
                 "\
 <!DOCTYPE html>
@@ -123,38 +600,55 @@ impl HttpRequest {
     </body>
 </html>
 ",
-            );
+            )
+            .into_bytes();
             let content_len = content.len();
-            Err(HttpResponse {
+            let mut headers = Headers::new();
+            headers.insert("Content-Type", "text/html; charset=utf-8");
+            return Err(HttpResponse {
                 code: HttpResponseCode::ImATeapot418,
-                content: Some(content),
+                content: Some(Body::Bytes(content)),
                 content_length: Some(content_len),
-            })
-        } else if self.fetch.find("//").is_some()
-            || self.fetch.find("..").is_some()
-            || self.fetch.ends_with('/')
-        {
+                headers,
+            });
+        }
+
+        // the checks run on the decoded path, so encoded dots or slashes can't bypass them
+        let path = match url::percent_decode(self.raw_path(), false) {
+            Some(path) => path,
+            None => return Err(HttpResponse::bad_request_400()),
+        };
+        if path.contains("//") || path.split('/').any(|segment| segment == "..") {
             Err(HttpResponse {
                 code: HttpResponseCode::Forbbiden403,
                 content: None,
                 content_length: None,
+                headers: Headers::new(),
             })
+        } else if path.starts_with('/') {
+            Ok(format!(".{path}"))
         } else {
-            let fetch = self.fetch.replace("%20", " ");
-            if fetch.starts_with('/') {
-                Ok(format!(".{}", fetch))
-            } else {
-                Ok(format!("./{}", fetch))
-            }
+            Ok(format!("./{path}"))
         }
     }
+
+    /// The path of the request target as sent, without the query and the fragment.
+    pub fn raw_path(&self) -> &str {
+        url::split_target(&self.fetch).0
+    }
+
+    /// The query of the request target as sent, if any.
+    pub fn raw_query(&self) -> Option<&str> {
+        url::split_target(&self.fetch).1
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct HttpResponse {
     pub code: HttpResponseCode,
-    pub content: Option<String>,
+    pub content: Option<Body>,
     pub content_length: Option<usize>,
+    pub headers: Headers,
 }
 
 impl HttpResponse {
@@ -163,33 +657,416 @@ impl HttpResponse {
             code: HttpResponseCode::BadRequest400,
             content: None,
             content_length: None,
+            headers: Headers::new(),
+        }
+    }
+
+    /// Response to a failure while reading a request: 408 if the client was too slow, else 400.
+    pub(crate) fn from_read_error(err: IoError) -> HttpResponse {
+        match err.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => HttpResponse {
+                code: HttpResponseCode::RequestTimeout408,
+                content: None,
+                content_length: None,
+                headers: Headers::new(),
+            },
+            _ => HttpResponse::bad_request_400(),
+        }
+    }
+}
+
+/// How the end of the content of a message is found (RFC 9112, section 6.3).
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Framing {
+    /// Exactly this many bytes follow the header section.
+    Length(u64),
+    /// The content comes in chunks, then trailer fields.
+    Chunked,
+}
+
+impl Framing {
+    /// The framing announced by `headers`, or `None` if they announce none. Fails with a 400 if
+    /// the `Content-Length` is invalid, or if both fields are present as the two ends of the
+    /// connection could disagree on where the message ends, and with a 501 for transfer codings
+    /// other than `chunked`.
+    pub(crate) fn of(headers: &Headers) -> Result<Option<Framing>, HttpResponseCode> {
+        if headers.contains("Transfer-Encoding") {
+            if headers.contains("Content-Length") {
+                return Err(HttpResponseCode::BadRequest400);
+            }
+            let codings: Vec<&str> = headers.get_list("Transfer-Encoding").collect();
+            return match codings[..] {
+                [coding] if coding.eq_ignore_ascii_case("chunked") => Ok(Some(Framing::Chunked)),
+                _ => Err(HttpResponseCode::NotImplemented501),
+            };
+        }
+        let mut lengths = headers.get_list("Content-Length");
+        match lengths.next() {
+            None => Ok(None),
+            Some(first) => match first.parse::<u64>() {
+                Ok(len)
+                    if first.bytes().all(|b| b.is_ascii_digit())
+                        && lengths.all(|other| other == first) =>
+                {
+                    Ok(Some(Framing::Length(len)))
+                }
+                _ => Err(HttpResponseCode::BadRequest400),
+            },
+        }
+    }
+}
+
+/// Reads a line of a chunked body, up to [`MAX_CHUNK_LINE`] bytes, without its ending.
+fn read_chunk_line(reader: &mut impl BufRead) -> IoResult<String> {
+    let mut line = Vec::new();
+    reader.take(MAX_CHUNK_LINE).read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\n") {
+        // the line is too long or the peer left
+        return Err(IoError::new(
+            ErrorKind::InvalidData,
+            "unterminated chunk line",
+        ));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|err| IoError::new(ErrorKind::InvalidData, err))
+}
+
+/// Reads the line starting a chunk and returns the size of the chunk, ignoring its extensions.
+/// The last chunk has a size of 0.
+fn read_chunk_size(reader: &mut impl BufRead) -> IoResult<u64> {
+    let line = read_chunk_line(reader)?;
+    let size = line.split(';').next().unwrap_or_default().trim();
+    match u64::from_str_radix(size, 16) {
+        Ok(len) if size.bytes().all(|b| b.is_ascii_hexdigit()) => Ok(len),
+        _ => Err(IoError::new(ErrorKind::InvalidData, "invalid chunk size")),
+    }
+}
+
+/// Reads the CRLF ending the data of a chunk.
+fn read_chunk_end(reader: &mut impl BufRead) -> IoResult<()> {
+    match read_chunk_line(reader)?.is_empty() {
+        true => Ok(()),
+        false => Err(IoError::new(
+            ErrorKind::InvalidData,
+            "chunk longer than its size",
+        )),
+    }
+}
+
+/// Reads the trailer section ending a chunked body, like a header section.
+fn read_trailers(reader: &mut impl BufRead) -> Result<Headers, HttpResponse> {
+    Headers::read_section(iter::from_fn(|| Some(read_chunk_line(reader))))
+}
+
+/// Decodes a chunked body (RFC 9112, section 7.1), of at most `limit` bytes, and its trailer
+/// fields.
+fn read_chunked(
+    reader: &mut impl BufRead,
+    limit: usize,
+) -> Result<(Vec<u8>, Headers), HttpResponse> {
+    let mut body = Vec::new();
+    loop {
+        let size = read_chunk_size(reader).map_err(HttpResponse::from_read_error)?;
+        if size == 0 {
+            break;
+        }
+        if body.len() as u64 + size > limit as u64 {
+            return Err(HttpResponse {
+                code: HttpResponseCode::ContentTooLarge413,
+                content: None,
+                content_length: None,
+                headers: Headers::new(),
+            });
+        }
+        match reader.take(size).read_to_end(&mut body) {
+            Ok(read) if read as u64 == size => (),
+            Ok(_) => return Err(HttpResponse::bad_request_400()),
+            Err(err) => return Err(HttpResponse::from_read_error(err)),
+        }
+        read_chunk_end(reader).map_err(HttpResponse::from_read_error)?;
+    }
+    Ok((body, read_trailers(reader)?))
+}
+
+/// Streams a chunked body from `reader` as it arrives, in pieces of at most [`BUFFER_SIZE`]
+/// bytes, like [`read_chunked`] but without a limit. The trailer fields are dropped. Fails with
+/// [`ErrorKind::InvalidData`] if the body is malformed.
+pub(crate) fn stream_chunked(mut reader: impl BufRead + Send + 'static) -> Chunks {
+    // bytes left in the current chunk, none before the first
+    let mut remaining = 0;
+    let mut started = false;
+    let mut done = false;
+    let mut next = move || -> IoResult<Option<Vec<u8>>> {
+        if remaining == 0 {
+            if started {
+                read_chunk_end(&mut reader)?;
+            }
+            started = true;
+            remaining = read_chunk_size(&mut reader)?;
+            if remaining == 0 {
+                return match read_trailers(&mut reader) {
+                    Ok(_) => Ok(None),
+                    Err(_) => Err(IoError::new(ErrorKind::InvalidData, "invalid trailers")),
+                };
+            }
+        }
+        let mut buffer = vec![0; remaining.min(BUFFER_SIZE as u64) as usize];
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        remaining -= read as u64;
+        buffer.truncate(read);
+        Ok(Some(buffer))
+    };
+    Box::new(iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        let piece = next().transpose();
+        done = !matches!(piece, Some(Ok(_)));
+        piece
+    }))
+}
+
+/// Whether `name` is a valid field name, a token of RFC 9110.
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Makes sure a streamed body produces exactly `len` bytes, failing otherwise. Extra bytes would
+/// be read as the next response and missing ones would leave the client waiting.
+fn exactly(chunks: Chunks, len: u64) -> Chunks {
+    let mut remaining = len;
+    let mut chunks = chunks.fuse();
+    Box::new(iter::from_fn(move || match chunks.next() {
+        Some(Ok(chunk)) if chunk.len() as u64 > remaining => {
+            remaining = 0;
+            Some(Err(IoError::new(
+                ErrorKind::InvalidData,
+                "body longer than its Content-Length",
+            )))
+        }
+        Some(Ok(chunk)) => {
+            remaining -= chunk.len() as u64;
+            Some(Ok(chunk))
+        }
+        Some(Err(err)) => Some(Err(err)),
+        None if remaining > 0 => {
+            remaining = 0;
+            Some(Err(ErrorKind::UnexpectedEof.into()))
+        }
+        None => None,
+    }))
+}
+
+/// Frames streamed content as chunks (RFC 9112, section 7.1), each in a single piece, and ends it
+/// with the last chunk. Empty pieces are skipped, as they would end the content early.
+fn chunked(chunks: Chunks) -> Chunks {
+    let mut chunks = chunks.fuse();
+    let mut done = false;
+    Box::new(iter::from_fn(move || loop {
+        if done {
+            return None;
+        }
+        match chunks.next() {
+            Some(Ok(chunk)) if chunk.is_empty() => continue,
+            Some(Ok(chunk)) => {
+                let mut framed = format!("{:x}\r\n", chunk.len()).into_bytes();
+                framed.extend_from_slice(&chunk);
+                framed.extend_from_slice(b"\r\n");
+                return Some(Ok(framed));
+            }
+            // without the last chunk, the client knows the content is incomplete
+            Some(Err(err)) => {
+                done = true;
+                return Some(Err(err));
+            }
+            None => {
+                done = true;
+                return Some(Ok(b"0\r\n\r\n".to_vec()));
+            }
+        }
+    }))
+}
+
+impl HttpResponseCode {
+    /// Whether a response with this status may have content. 1xx, 204 and 304 responses never do
+    /// (RFC 9110, section 6.4.1), so they end with their header section.
+    pub fn allows_content(&self) -> bool {
+        match self {
+            HttpResponseCode::Continue100 | HttpResponseCode::NotModified304 => false,
+            HttpResponseCode::Other(code, _) => *code >= 200 && *code != 204 && *code != 304,
+            _ => true,
+        }
+    }
+
+    /// The variant of the status `code`, or [`HttpResponseCode::Other`] with `reason` if it has
+    /// none. Control characters of the reason are dropped.
+    ///
+    /// # Examples
+    /// ```
+    /// use qst::http::HttpResponseCode;
+    /// assert_eq!(HttpResponseCode::NotFound404, HttpResponseCode::from_status(404, "Nope"));
+    /// assert_eq!("201 Created", HttpResponseCode::from_status(201, "Created").to_string());
+    /// ```
+    pub fn from_status(code: u16, reason: &str) -> HttpResponseCode {
+        match code {
+            100 => HttpResponseCode::Continue100,
+            200 => HttpResponseCode::OK200,
+            206 => HttpResponseCode::PartialContent206,
+            301 => HttpResponseCode::MovedPermanently301,
+            304 => HttpResponseCode::NotModified304,
+            400 => HttpResponseCode::BadRequest400,
+            403 => HttpResponseCode::Forbbiden403,
+            404 => HttpResponseCode::NotFound404,
+            405 => HttpResponseCode::MethodNotAllowed405,
+            408 => HttpResponseCode::RequestTimeout408,
+            413 => HttpResponseCode::ContentTooLarge413,
+            416 => HttpResponseCode::RangeNotSatisfiable416,
+            417 => HttpResponseCode::ExpectationFailed417,
+            418 => HttpResponseCode::ImATeapot418,
+            431 => HttpResponseCode::RequestHeaderFieldsTooLarge431,
+            501 => HttpResponseCode::NotImplemented501,
+            502 => HttpResponseCode::BadGateway502,
+            504 => HttpResponseCode::GatewayTimeout504,
+            505 => HttpResponseCode::HttpVersionNotSupported505,
+            _ => {
+                let reason = reason.replace(|c: char| c.is_control(), "");
+                HttpResponseCode::Other(code, reason.trim().to_string())
+            }
         }
     }
 }
 
-impl ToString for HttpResponse {
-    fn to_string(&self) -> String {
+impl HttpResponse {
+    /// The length sent in `Content-Length`: `content_length` if set, else the size of the content.
+    /// It is known unless the content is generated.
+    fn length(&self) -> Option<usize> {
+        match &self.content {
+            _ if !self.code.allows_content() => None,
+            _ if self.content_length.is_some() => self.content_length,
+            Some(content) => content.size().map(|size| size as usize),
+            None => Some(0),
+        }
+    }
+
+    /// Whether the content goes out in chunks: its length is unknown and `Transfer-Encoding` was
+    /// set to `chunked`, which only HTTP/1.1 clients understand. When the length is known, the
+    /// field is ignored and `Content-Length` is sent instead.
+    pub fn is_chunked(&self) -> bool {
+        self.code.allows_content()
+            && self.length().is_none()
+            && self
+                .headers
+                .get_list("Transfer-Encoding")
+                .any(|coding| coding.eq_ignore_ascii_case("chunked"))
+    }
+
+    /// Whether the end of the response is known to the client without closing the connection.
+    pub fn is_delimited(&self) -> bool {
+        !self.code.allows_content() || self.length().is_some() || self.is_chunked()
+    }
+
+    /// Serializes the status line and the header section (RFC 9112, section 2.1).
+    fn head(&self) -> Vec<u8> {
         // add statusline
-        let mut http_response = String::from("HTTP/1.1 ");
-        http_response.push_str(&self.code.to_string()[..]);
-        http_response.push('\r');
-        http_response.push('\n');
+        let mut head = format!("HTTP/1.1 {}\r\n", self.code).into_bytes();
+
+        // add headers
+        self.headers.write_lines(&mut head);
 
-        // add content_length if applicable
-        if let Some(length) = self.content_length {
-            http_response.push_str(&format!("Content-Length: {length}\r\n\r\n")[..])
+        // add content_length if applicable, else chunk the content if asked to
+        if let Some(length) = self.length() {
+            head.extend_from_slice(format!("Content-Length: {length}\r\n").as_bytes());
+        } else if self.is_chunked() {
+            head.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+        }
+
+        // end the header section
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+
+    /// Splits the response into its head, with the content when it is in memory so both go out
+    /// in a single write, and the body left to stream. Fails before anything is written if the
+    /// content doesn't match `content_length`.
+    fn into_parts(self) -> IoResult<(Vec<u8>, Option<Body>)> {
+        let mut head = self.head();
+        let is_chunked = self.is_chunked();
+        let body = match self.content {
+            Some(body) if self.code.allows_content() => body,
+            _ => return Ok((head, None)),
+        };
+        let declared = self.content_length.map(|len| len as u64);
+        if let (Some(declared), Some(size)) = (declared, body.size()) {
+            if declared != size {
+                return Err(IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!("content of {size} bytes sent with a Content-Length of {declared}"),
+                ));
+            }
+        }
+        match (body, declared) {
+            (Body::Bytes(bytes), _) => {
+                head.extend_from_slice(&bytes);
+                Ok((head, None))
+            }
+            (Body::Chunks(chunks), Some(declared)) => {
+                Ok((head, Some(Body::Chunks(exactly(chunks, declared)))))
+            }
+            (Body::Chunks(chunks), None) if is_chunked => {
+                Ok((head, Some(Body::Chunks(chunked(chunks)))))
+            }
+            (body, _) => Ok((head, Some(body))),
         }
+    }
 
-        // add content if applicable
-        if let Some(content) = &self.content {
-            http_response.push_str(&format!("{content}\r\n")[..]);
+    /// Writes the response to `out`: the status line, the header fields, a single blank line and
+    /// then exactly `content_length` bytes of content. The content is copied as-is, so binary files
+    /// are sent unchanged, and nothing follows it, else a persistent connection would read the
+    /// extra bytes as the start of the next response. Without a `content_length`, the content is
+    /// sent in chunks if [`HttpResponse::is_chunked`], else delimited by closing the connection.
+    pub fn write_to(self, out: &mut impl Write) -> IoResult<()> {
+        let (head, body) = self.into_parts()?;
+        out.write_all(&head)?;
+        match body {
+            Some(body) => body.write_to(out),
+            None => Ok(()),
         }
+    }
+
+    /// Writes the status line and the header fields of the response to `out`, without the content,
+    /// as the answer to a `HEAD` request (RFC 9110, section 9.3.2). The fields are the ones
+    /// [`HttpResponse::write_to`] would send, `Content-Length` included when known, so the
+    /// response ends with its header section and the connection can be reused.
+    pub fn write_head_to(self, out: &mut impl Write) -> IoResult<()> {
+        out.write_all(&self.head())
+    }
 
-        // end and return response
-        http_response.push('\r');
-        http_response.push('\n');
+    /// Writes the response to `stream`, like [`HttpResponse::write_to`], but lets the kernel send
+    /// files when it can. See [`Body::send_to`].
+    pub fn send_to(self, mut stream: &TcpStream) -> IoResult<()> {
+        let (head, body) = self.into_parts()?;
+        stream.write_all(&head)?;
+        match body {
+            Some(body) => body.send_to(stream),
+            None => Ok(()),
+        }
+    }
 
-        http_response
+    /// Serializes the whole response into the bytes to be written to the wire, reading streamed
+    /// content into memory.
+    pub fn to_bytes(self) -> IoResult<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes)?;
+        Ok(bytes)
     }
 }
 
@@ -207,6 +1084,11 @@ mod tests {
             HttpRequest {
                 method: HttpMethod::GET,
                 fetch: String::from("/"),
+                query: HashMap::new(),
+                version: HttpVersion::Http11,
+                headers: Headers::new(),
+                body: Vec::new(),
+                trailers: Headers::new(),
             }
         );
 
@@ -217,6 +1099,11 @@ mod tests {
             HttpRequest {
                 method: HttpMethod::GET,
                 fetch: String::from("/index.html"),
+                query: HashMap::new(),
+                version: HttpVersion::Http10,
+                headers: Headers::new(),
+                body: Vec::new(),
+                trailers: Headers::new(),
             }
         );
 
@@ -225,11 +1112,18 @@ mod tests {
             IoResult::Ok(String::from("Host: pudim.com.br")),
         ];
         let response = HttpRequest::parse_from_lines_iterator(request.into_iter()).unwrap();
+        let mut headers = Headers::new();
+        headers.append("Host", "pudim.com.br");
         assert_eq!(
             response,
             HttpRequest {
                 method: HttpMethod::GET,
                 fetch: String::from("/"),
+                query: HashMap::new(),
+                version: HttpVersion::Http10,
+                headers,
+                body: Vec::new(),
+                trailers: Headers::new(),
             }
         );
 
@@ -240,10 +1134,134 @@ mod tests {
             HttpRequest {
                 method: HttpMethod::HEAD,
                 fetch: String::from("/index.html"),
+                query: HashMap::new(),
+                version: HttpVersion::Http10,
+                headers: Headers::new(),
+                body: Vec::new(),
+                trailers: Headers::new(),
             }
         );
     }
 
+    #[test]
+    fn parser_reads_headers_up_to_blank_line() {
+        let request = vec![
+            IoResult::Ok(String::from("GET /app.js HTTP/1.1")),
+            IoResult::Ok(String::from("Host: localhost:6969")),
+            IoResult::Ok(String::from("accept-encoding:gzip, br")),
+            IoResult::Ok(String::from("Accept-Encoding:   zstd  ")),
+            IoResult::Ok(String::from("X-Empty:")),
+            IoResult::Ok(String::from("")),
+            IoResult::Ok(String::from("GET /next.js HTTP/1.1")),
+        ];
+        let request = HttpRequest::parse_from_lines_iterator(request.into_iter()).unwrap();
+        assert_eq!(4, request.headers.len());
+        assert_eq!(Some("localhost:6969"), request.headers.get("host"));
+        assert_eq!(Some(""), request.headers.get("x-empty"));
+        assert_eq!(
+            vec!["gzip, br", "zstd"],
+            request
+                .headers
+                .get_all("Accept-Encoding")
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["gzip", "br", "zstd"],
+            request
+                .headers
+                .get_list("ACCEPT-ENCODING")
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn parser_leaves_pipelined_requests_in_the_reader() {
+        let mut reader = BufReader::new(
+            &b"GET /a.js HTTP/1.1\r\nHost: x\r\n\r\nHEAD /b.css HTTP/1.1\r\n\r\n"[..],
+        );
+        let request = HttpRequest::parse_from_lines_iterator((&mut reader).lines()).unwrap();
+        assert_eq!("/a.js", request.fetch);
+        let request = HttpRequest::parse_from_lines_iterator((&mut reader).lines()).unwrap();
+        assert_eq!("/b.css", request.fetch);
+        assert_eq!(HttpMethod::HEAD, request.method);
+        assert!(reader.fill_buf().unwrap().is_empty());
+    }
+
+    #[test]
+    fn parser_returns_bad_request_on_invalid_headers() {
+        for line in ["Host pudim.com.br", "Bad Name: value", ": value", " folded"] {
+            let request = vec![
+                IoResult::Ok(String::from("GET / HTTP/1.1")),
+                IoResult::Ok(String::from(line)),
+            ];
+            let response = HttpRequest::parse_from_lines_iterator(request.into_iter()).unwrap_err();
+            assert_eq!(HttpResponse::bad_request_400(), response);
+        }
+
+        let mut request = vec![IoResult::Ok(String::from("GET / HTTP/1.1"))];
+        for i in 0..=MAX_REQUEST_HEADERS {
+            request.push(IoResult::Ok(format!("X-Header-{i}: {i}")));
+        }
+        let response = HttpRequest::parse_from_lines_iterator(request.into_iter()).unwrap_err();
+        assert_eq!(
+            response.code,
+            HttpResponseCode::RequestHeaderFieldsTooLarge431
+        );
+    }
+
+    #[test]
+    fn headers_are_case_insensitive_and_multi_valued() {
+        let mut headers = Headers::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("set-cookie", "b=2");
+        headers.insert("Content-Type", "text/plain");
+        assert!(headers.contains("SET-COOKIE"));
+        assert_eq!(2, headers.get_all("Set-Cookie").count());
+
+        headers.insert("CONTENT-TYPE", "text/html");
+        assert_eq!(Some("text/html"), headers.get("content-type"));
+        assert_eq!(1, headers.get_all("content-type").count());
+
+        headers.remove("set-COOKIE");
+        assert!(!headers.contains("Set-Cookie"));
+        assert_eq!(
+            vec![("CONTENT-TYPE", "text/html")],
+            headers.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn parser_reads_version_and_connection() {
+        let parse = |lines: &[&str]| {
+            let lines = lines.iter().map(|line| IoResult::Ok(line.to_string()));
+            HttpRequest::parse_from_lines_iterator(lines)
+        };
+
+        let request = parse(&["GET / HTTP/1.1"]).unwrap();
+        assert!(request.keep_alive());
+        let request = parse(&["GET / HTTP/1.1", "Connection: Close"]).unwrap();
+        assert!(!request.keep_alive());
+        let request = parse(&["GET / HTTP/1.0"]).unwrap();
+        assert!(!request.keep_alive());
+        let request = parse(&["GET / HTTP/1.0", "Connection: Keep-Alive"]).unwrap();
+        assert!(request.keep_alive());
+        let request = parse(&["GET /"]).unwrap();
+        assert_eq!(HttpVersion::Http10, request.version);
+        assert!(!request.keep_alive());
+
+        let response = parse(&["GET / HTTP/2.0"]).unwrap_err();
+        assert_eq!(HttpResponseCode::HttpVersionNotSupported505, response.code);
+        let response = parse(&["GET / FTP/1.1"]).unwrap_err();
+        assert_eq!(HttpResponseCode::BadRequest400, response.code);
+
+        let lines = vec![
+            IoResult::Ok(String::from("GET / HTTP/1.1")),
+            IoResult::Err(IoError::from(ErrorKind::WouldBlock)),
+        ];
+        let response = HttpRequest::parse_from_lines_iterator(lines.into_iter()).unwrap_err();
+        assert_eq!(HttpResponseCode::RequestTimeout408, response.code);
+    }
+
     #[test]
     fn parser_returns_bad_request_on_invalid() {
         let request = vec![IoResult::Ok(String::from("GET"))];
@@ -261,7 +1279,7 @@ mod tests {
 
     #[test]
     fn parser_returns_not_implemented_on_methods() {
-        let request = vec![IoResult::Ok(String::from("POST / HTTP/1.1"))];
+        let request = vec![IoResult::Ok(String::from("BREW / HTTP/1.1"))];
         let response = HttpRequest::parse_from_lines_iterator(request.into_iter()).unwrap_err();
         assert_eq!(
             response,
@@ -269,21 +1287,224 @@ mod tests {
                 code: HttpResponseCode::NotImplemented501,
                 content: None,
                 content_length: None,
+                headers: Headers::new(),
             }
         );
+        let request = vec![IoResult::Ok(String::from("get / HTTP/1.1"))];
+        let response = HttpRequest::parse_from_lines_iterator(request.into_iter()).unwrap_err();
+        assert_eq!(HttpResponseCode::NotImplemented501, response.code);
     }
 
     #[test]
-    fn response_to_string_creates_correct_responses() {
+    fn parser_accepts_every_method() {
+        for method in [
+            HttpMethod::GET,
+            HttpMethod::HEAD,
+            HttpMethod::POST,
+            HttpMethod::PUT,
+            HttpMethod::DELETE,
+            HttpMethod::CONNECT,
+            HttpMethod::OPTIONS,
+            HttpMethod::TRACE,
+            HttpMethod::PATCH,
+        ] {
+            let line = format!("{} / HTTP/1.1", method.as_str());
+            let request = vec![IoResult::Ok(line)];
+            let request = HttpRequest::parse_from_lines_iterator(request.into_iter()).unwrap();
+            assert_eq!(method, request.method);
+        }
+    }
+
+    fn read_body(wire: &[u8], limit: usize) -> Result<(Vec<u8>, Vec<u8>), HttpResponseCode> {
+        let mut reader = BufReader::new(wire);
+        let mut request = HttpRequest::parse_from_lines_iterator((&mut reader).lines()).unwrap();
+        match request.read_body(&mut reader, limit) {
+            Ok(()) => Ok((request.body, reader.fill_buf().unwrap().to_vec())),
+            Err(response) => Err(response.code),
+        }
+    }
+
+    #[test]
+    fn bodies_are_read_by_content_length() {
+        assert_eq!(
+            Ok((b"hello".to_vec(), b"GET / HTTP/1.1\r\n\r\n".to_vec())),
+            read_body(
+                b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.1\r\n\r\n",
+                1024
+            )
+        );
+        assert_eq!(
+            Ok((Vec::new(), b"x".to_vec())),
+            read_body(b"DELETE / HTTP/1.1\r\n\r\nx", 1024)
+        );
+        assert_eq!(
+            Ok((b"ab".to_vec(), Vec::new())),
+            read_body(b"PUT / HTTP/1.1\r\nContent-Length: 2, 2\r\n\r\nab", 1024)
+        );
+        for wire in [
+            &b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhell"[..],
+            b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello",
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 4\r\n\r\nhello",
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n",
+        ] {
+            assert_eq!(Err(HttpResponseCode::BadRequest400), read_body(wire, 1024));
+        }
+        assert_eq!(
+            Err(HttpResponseCode::ContentTooLarge413),
+            read_body(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello", 4)
+        );
+    }
+
+    #[test]
+    fn expectations_are_answered_before_the_body() {
+        let answer = |head: &str, limit| {
+            let lines = head.split("\r\n").map(|line| Ok(line.to_string()));
+            let request = HttpRequest::parse_from_lines_iterator(lines).unwrap();
+            let mut out = Vec::new();
+            let answer = request.answer_expectation(&mut out, limit);
+            (answer.map_err(|response| response.code), out)
+        };
+        let interim = b"HTTP/1.1 100 Continue\r\n\r\n".to_vec();
+        assert_eq!(
+            (Ok(()), interim.clone()),
+            answer(
+                "PUT / HTTP/1.1\r\nExpect: 100-Continue\r\nContent-Length: 4",
+                4
+            )
+        );
+        assert_eq!(
+            (Ok(()), interim),
+            answer(
+                "PUT / HTTP/1.1\r\nExpect: 100-continue\r\nTransfer-Encoding: chunked",
+                4
+            )
+        );
+        assert_eq!(
+            (Err(HttpResponseCode::ContentTooLarge413), Vec::new()),
+            answer(
+                "PUT / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5",
+                4
+            )
+        );
+        assert_eq!(
+            (Err(HttpResponseCode::ExpectationFailed417), Vec::new()),
+            answer("PUT / HTTP/1.1\r\nExpect: teapot\r\nContent-Length: 4", 4)
+        );
+        // without a body or from an HTTP/1.0 client, there is nothing to answer
+        for head in [
+            "GET / HTTP/1.1\r\nExpect: 100-continue",
+            "PUT / HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 5",
+        ] {
+            assert_eq!((Ok(()), Vec::new()), answer(head, 4), "{head}");
+        }
+    }
+
+    #[test]
+    fn chunked_bodies_are_decoded() {
+        let wire = b"PATCH / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+5;name=value\r\nhello\r\nA\r\n, world!\r\n\r\n0\r\nDigest: x\r\n\r\nnext";
+        assert_eq!(
+            Ok((b"hello, world!\r\n".to_vec(), b"next".to_vec())),
+            read_body(wire, 1024)
+        );
+        assert_eq!(
+            Err(HttpResponseCode::ContentTooLarge413),
+            read_body(wire, 14)
+        );
+        let mut reader = BufReader::new(&wire[..]);
+        let mut request = HttpRequest::parse_from_lines_iterator((&mut reader).lines()).unwrap();
+        request.read_body(&mut reader, 1024).unwrap();
+        assert_eq!(Some("x"), request.trailers.get("digest"));
+        assert_eq!(1, request.trailers.len());
+        assert!(!request.headers.contains("Digest"));
+        for wire in [
+            &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello0\r\n\r\n"[..],
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nx\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nbad\r\n\r\n",
+        ] {
+            assert_eq!(Err(HttpResponseCode::BadRequest400), read_body(wire, 1024));
+        }
+        assert_eq!(
+            Err(HttpResponseCode::NotImplemented501),
+            read_body(
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n",
+                1024
+            )
+        );
+    }
+
+    #[test]
+    fn chunked_bodies_are_streamed() {
+        let wire = b"3\r\nabc\r\n2;ext\r\nde\r\n0\r\nX: y\r\n\r\nnext";
+        let chunks = stream_chunked(BufReader::new(&wire[..]));
+        let pieces: Vec<Vec<u8>> = chunks.map(Result::unwrap).collect();
+        assert_eq!(vec![b"abc".to_vec(), b"de".to_vec()], pieces);
+
+        for wire in [&b"3\r\nabcd\r\n0\r\n\r\n"[..], b"z\r\n", b"3\r\nab"] {
+            let last = stream_chunked(BufReader::new(wire)).last().unwrap();
+            assert!(last.is_err(), "{wire:?}");
+        }
+    }
+
+    #[test]
+    fn requests_are_written_with_a_content_length() {
+        let mut headers = Headers::new();
+        headers.append("Host", "upstream");
+        headers.append("Transfer-Encoding", "chunked");
+        headers.append("X-Split", "a\r\nb");
+        let request = HttpRequest {
+            method: HttpMethod::POST,
+            fetch: String::from("/a%20b?c"),
+            query: HashMap::new(),
+            version: HttpVersion::Http10,
+            headers,
+            body: Vec::new(),
+            trailers: Headers::new(),
+        };
+        let mut wire = Vec::new();
+        request.write_to(&mut wire).unwrap();
+        assert_eq!(
+            "POST /a%20b?c HTTP/1.0\r\nHost: upstream\r\nX-Split: a  b\r\nContent-Length: 0\r\n\r\n",
+            String::from_utf8(wire).unwrap()
+        );
+
+        let request = HttpRequest {
+            method: HttpMethod::DELETE,
+            fetch: String::from("/"),
+            query: HashMap::new(),
+            version: HttpVersion::Http11,
+            headers: Headers::new(),
+            body: b"body".to_vec(),
+            trailers: Headers::new(),
+        };
+        let mut wire = Vec::new();
+        request.write_to(&mut wire).unwrap();
+        assert_eq!(
+            b"DELETE / HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody".to_vec(),
+            wire
+        );
+    }
+
+    #[test]
+    fn response_to_bytes_creates_correct_responses() {
         let response = HttpResponse {
             code: HttpResponseCode::NotFound404,
             content: None,
             content_length: None,
+            headers: Headers::new(),
         };
-        assert_eq!(response.to_string(), "HTTP/1.1 404 Not Found\r\n\r\n");
+        assert_eq!(
+            response.to_bytes().unwrap(),
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+        );
 
         let response = HttpResponse::bad_request_400();
-        assert_eq!(response.to_string(), "HTTP/1.1 400 Bad Request\r\n\r\n");
+        assert_eq!(
+            response.to_bytes().unwrap(),
+            b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n"
+        );
 
         let content = "\
 <!DOCTYPE html>
@@ -297,12 +1518,13 @@ mod tests {
 ";
         let response = HttpResponse {
             code: HttpResponseCode::OK200,
-            content: Some(String::from(content)),
+            content: Some(Body::Bytes(content.as_bytes().to_vec())),
             content_length: Some(content.len()),
+            headers: Headers::new(),
         };
 
         assert_eq!(
-            response.to_string(),
+            response.to_bytes().unwrap(),
             String::from(
                 "\
 HTTP/1.1 200 Ok\r
@@ -316,11 +1538,41 @@ Content-Length: 99\r
         Hello, World!
     </body>
 </html>
-\r
-\r
 "
             )
+            .into_bytes()
         );
+
+        let content = [
+            0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', 0x00, 0xff,
+        ];
+        let response = HttpResponse {
+            code: HttpResponseCode::OK200,
+            content: Some(Body::Bytes(content.to_vec())),
+            content_length: Some(content.len()),
+            headers: Headers::new(),
+        };
+        let bytes = response.to_bytes().unwrap();
+        let header = b"HTTP/1.1 200 Ok\r\nContent-Length: 10\r\n\r\n";
+        assert_eq!(&bytes[..header.len()], header);
+        assert_eq!(&bytes[header.len()..header.len() + content.len()], content);
+
+        let mut headers = Headers::new();
+        headers.insert("Content-Type", "image/png");
+        headers.append("X-Injected", "a\r\nSet-Cookie: b");
+        let response = HttpResponse {
+            code: HttpResponseCode::OK200,
+            content: Some(Body::Bytes(content.to_vec())),
+            content_length: Some(content.len()),
+            headers,
+        };
+        let bytes = response.to_bytes().unwrap();
+        let header = b"HTTP/1.1 200 Ok\r\n\
+Content-Type: image/png\r\n\
+X-Injected: a  Set-Cookie: b\r\n\
+Content-Length: 10\r\n\r\n";
+        assert_eq!(&bytes[..header.len()], header);
+        assert_eq!(&bytes[header.len()..header.len() + content.len()], content);
     }
 
     #[test]
@@ -329,37 +1581,404 @@ Content-Length: 99\r
             code: HttpResponseCode::Forbbiden403,
             content: None,
             content_length: None,
+            headers: Headers::new(),
         });
 
         let mut request = HttpRequest {
             method: HttpMethod::GET,
             fetch: String::from("/"),
+            query: HashMap::new(),
+            version: HttpVersion::Http10,
+            headers: Headers::new(),
+            body: Vec::new(),
+            trailers: Headers::new(),
         };
 
+        assert_eq!(Ok(String::from("./")), request.match_fetch());
+
+        request.fetch = String::from("/test.js");
+        assert_eq!(Ok(String::from("./test.js")), request.match_fetch());
+
+        request.fetch = String::from("stuff.css");
+        assert_eq!(Ok(String::from("./stuff.css")), request.match_fetch());
+
+        request.fetch = String::from("../not_allow.png");
+        assert_eq!(forbidden_res, request.match_fetch());
+
+        request.fetch = String::from("/not//allow.jpg");
+        assert_eq!(forbidden_res, request.match_fetch());
+
+        request.fetch = String::from("/a/../../not_allow.png");
+        assert_eq!(forbidden_res, request.match_fetch());
+
+        request.fetch = String::from("/a/..");
+        assert_eq!(forbidden_res, request.match_fetch());
+
+        request.fetch = String::from("/foo..bar.js");
+        assert_eq!(Ok(String::from("./foo..bar.js")), request.match_fetch());
+
+        request.fetch = String::from("/..well-known/.../x..");
         assert_eq!(
-            Ok(String::from("./index.html")),
-            request.match_fetch("index.html")
+            Ok(String::from("./..well-known/.../x..")),
+            request.match_fetch()
         );
 
-        request.fetch = String::from("/test.js");
+        request.fetch = String::from("/a/directory/");
+        assert_eq!(Ok(String::from("./a/directory/")), request.match_fetch());
+    }
+
+    #[test]
+    fn http_request_decodes_fetch_before_checking_it() {
+        let parse = |target: &str| {
+            let lines = [IoResult::Ok(format!("GET {target} HTTP/1.1"))];
+            HttpRequest::parse_from_lines_iterator(lines.into_iter())
+        };
+
+        let request = parse("/caf%C3%A9/a%20b%23c+d.js?v=3&name=a+b#top").unwrap();
+        assert_eq!(Ok(String::from("./café/a b#c+d.js")), request.match_fetch());
+        assert_eq!("3", request.query["v"]);
+        assert_eq!("a b", request.query["name"]);
+        assert_eq!("/caf%C3%A9/a%20b%23c+d.js", request.raw_path());
+        assert_eq!(Some("v=3&name=a+b"), request.raw_query());
+
+        for target in ["/%2e%2e/secret", "/a/%2E%2E%2Fsecret", "/a%2F%2Fb"] {
+            let response = parse(target).unwrap().match_fetch().unwrap_err();
+            assert_eq!(HttpResponseCode::Forbbiden403, response.code);
+        }
+        for target in ["/%C0%AE%C0%AE/secret", "/a%", "/%00.js"] {
+            let response = parse(target).unwrap().match_fetch().unwrap_err();
+            assert_eq!(HttpResponseCode::BadRequest400, response.code);
+        }
         assert_eq!(
-            Ok(String::from("./test.js")),
-            request.match_fetch("index.html")
+            HttpResponse::bad_request_400(),
+            parse("/app.js?v=%zz").unwrap_err()
         );
+    }
 
-        request.fetch = String::from("stuff.css");
+    #[test]
+    fn http_request_checks_validators() {
+        let modified = UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let etag = weak_etag(42, modified);
+        let request = |headers: &[(&str, &str)]| {
+            let mut request = HttpRequest {
+                method: HttpMethod::GET,
+                fetch: String::from("/"),
+                query: HashMap::new(),
+                version: HttpVersion::Http11,
+                headers: Headers::new(),
+                body: Vec::new(),
+                trailers: Headers::new(),
+            };
+            for (name, value) in headers {
+                request.headers.append(name, value);
+            }
+            request
+        };
+
+        assert!(!request(&[]).is_not_modified(&etag, modified));
+        let matching = format!("\"other\", {}", etag.trim_start_matches("W/"));
+        assert!(request(&[("If-None-Match", &matching)]).is_not_modified(&etag, modified));
+        assert!(request(&[("If-None-Match", "*")]).is_not_modified(&etag, modified));
+        assert!(!request(&[("If-None-Match", "W/\"other\"")]).is_not_modified(&etag, modified));
+
+        let since = "Tue, 14 Nov 2023 22:13:20 GMT";
+        assert!(request(&[("If-Modified-Since", since)]).is_not_modified(&etag, modified));
+        let before = "Tue, 14 Nov 2023 22:13:19 GMT";
+        assert!(!request(&[("If-Modified-Since", before)]).is_not_modified(&etag, modified));
+        assert!(!request(&[("If-Modified-Since", "yesterday")]).is_not_modified(&etag, modified));
+        // If-None-Match wins
+        let both = [("If-None-Match", "\"other\""), ("If-Modified-Since", since)];
+        assert!(!request(&both).is_not_modified(&etag, modified));
+    }
+
+    #[test]
+    fn http_request_checks_if_range() {
+        let modified = UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let mut request = HttpRequest {
+            method: HttpMethod::GET,
+            fetch: String::from("/"),
+            query: HashMap::new(),
+            version: HttpVersion::Http11,
+            headers: Headers::new(),
+            body: Vec::new(),
+            trailers: Headers::new(),
+        };
+        assert!(request.if_range_matches("W/\"a\"", modified));
+
+        request
+            .headers
+            .insert("If-Range", "Tue, 14 Nov 2023 22:13:20 GMT");
+        assert!(request.if_range_matches("W/\"a\"", modified));
+        request
+            .headers
+            .insert("If-Range", "Tue, 14 Nov 2023 22:13:21 GMT");
+        assert!(!request.if_range_matches("W/\"a\"", modified));
+
+        request.headers.insert("If-Range", "\"a\"");
+        assert!(request.if_range_matches("\"a\"", modified));
+        assert!(!request.if_range_matches("W/\"a\"", modified));
+        request.headers.insert("If-Range", "W/\"a\"");
+        assert!(!request.if_range_matches("W/\"a\"", modified));
+    }
+
+    /// A response as read by [`parse_wire`].
+    #[derive(Debug, PartialEq)]
+    struct WireResponse {
+        status: u16,
+        fields: Vec<(String, String)>,
+        content: Vec<u8>,
+    }
+
+    /// A strict RFC 9112 parser for a stream of responses, independent from the writer: every
+    /// line must end with CRLF, field names must be tokens, values can't hold control characters
+    /// and the content is framed by a single valid Content-Length, by chunks, or by the end of the
+    /// stream for the last response. Panics on anything else.
+    fn parse_wire(mut wire: &[u8]) -> Vec<WireResponse> {
+        fn line<'a>(wire: &mut &'a [u8]) -> &'a str {
+            let end = wire.windows(2).position(|w| w == b"\r\n").expect("CRLF");
+            let line = std::str::from_utf8(&wire[..end]).unwrap();
+            assert!(!line.contains(['\r', '\n']), "bare CR or LF in {line:?}");
+            *wire = &wire[end + 2..];
+            line
+        }
+
+        let mut responses = Vec::new();
+        while !wire.is_empty() {
+            let status_line = line(&mut wire);
+            let rest = status_line.strip_prefix("HTTP/1.1 ").expect("HTTP/1.1");
+            let (code, reason) = rest.split_once(' ').expect("reason phrase");
+            assert!(code.len() == 3 && code.bytes().all(|b| b.is_ascii_digit()));
+            assert!(!reason.is_empty());
+            let status: u16 = code.parse().unwrap();
+
+            let mut fields = Vec::new();
+            loop {
+                let field = line(&mut wire);
+                if field.is_empty() {
+                    break;
+                }
+                assert!(!field.starts_with([' ', '\t']), "obsolete folding");
+                let (name, value) = field.split_once(':').expect("colon");
+                assert!(is_token(name), "invalid name {name:?}");
+                let value = value.trim_matches([' ', '\t']);
+                assert!(!value.chars().any(|c| c.is_ascii_control() && c != '\t'));
+                fields.push((name.to_string(), value.to_string()));
+            }
+
+            let lengths: Vec<_> = fields
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+                .map(|(_, value)| value.parse::<usize>().expect("valid Content-Length"))
+                .collect();
+            assert!(lengths.len() <= 1, "repeated Content-Length");
+            let chunked = fields
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case("Transfer-Encoding"));
+            if chunked {
+                assert!(lengths.is_empty(), "Content-Length with Transfer-Encoding");
+                assert!(
+                    fields.contains(&(String::from("Transfer-Encoding"), String::from("chunked")))
+                );
+                let mut content = Vec::new();
+                loop {
+                    let size = line(&mut wire);
+                    assert!(size.bytes().all(|b| b.is_ascii_hexdigit()), "chunk size");
+                    let size = usize::from_str_radix(size, 16).unwrap();
+                    if size == 0 {
+                        assert!(line(&mut wire).is_empty(), "trailer section");
+                        break;
+                    }
+                    content.extend_from_slice(&wire[..size]);
+                    wire = &wire[size..];
+                    assert!(line(&mut wire).is_empty(), "chunk end");
+                }
+                responses.push(WireResponse {
+                    status,
+                    fields,
+                    content,
+                });
+                continue;
+            }
+            let length = if (100..200).contains(&status) || status == 304 {
+                assert!(lengths.is_empty(), "Content-Length without content");
+                0
+            } else {
+                lengths.first().copied().unwrap_or(wire.len())
+            };
+            assert!(wire.len() >= length, "truncated content");
+            responses.push(WireResponse {
+                status,
+                fields,
+                content: wire[..length].to_vec(),
+            });
+            wire = &wire[length..];
+        }
+        responses
+    }
+
+    #[test]
+    fn written_responses_parse_strictly() {
+        let mut headers = Headers::new();
+        headers.insert("Content-Type", "text/plain");
+        headers.insert("Content-Length", "999");
+        headers.insert("Bad Name", "dropped");
+        let mut chunked = Headers::new();
+        chunked.insert("Transfer-Encoding", "chunked");
+        headers.insert("X-Control", "a\0b\x1bc\td ");
+        let responses = [
+            HttpResponse {
+                code: HttpResponseCode::NotFound404,
+                content: None,
+                content_length: Some(0),
+                headers: Headers::new(),
+            },
+            HttpResponse {
+                code: HttpResponseCode::OK200,
+                content: Some(Body::Bytes(b"hello\r\n\r\n".to_vec())),
+                content_length: Some(9),
+                headers,
+            },
+            HttpResponse {
+                code: HttpResponseCode::NotModified304,
+                content: Some(Body::Bytes(b"never sent".to_vec())),
+                content_length: Some(10),
+                headers: Headers::new(),
+            },
+            HttpResponse {
+                code: HttpResponseCode::OK200,
+                content: Some(Body::Chunks(Box::new(
+                    [b"ab".to_vec(), Vec::new(), b"c".to_vec()]
+                        .into_iter()
+                        .map(Ok),
+                ))),
+                content_length: Some(3),
+                headers: Headers::new(),
+            },
+            HttpResponse {
+                code: HttpResponseCode::OK200,
+                content: Some(Body::Chunks(Box::new(
+                    [b"chunk".to_vec(), Vec::new(), vec![b'x'; 300]]
+                        .into_iter()
+                        .map(Ok),
+                ))),
+                content_length: None,
+                headers: chunked.clone(),
+            },
+            // the length is known, no need for chunks
+            HttpResponse {
+                code: HttpResponseCode::OK200,
+                content: Some(Body::Bytes(b"known".to_vec())),
+                content_length: None,
+                headers: chunked,
+            },
+            // delimited by the end of the stream
+            HttpResponse {
+                code: HttpResponseCode::OK200,
+                content: Some(Body::Bytes(b"last".to_vec())),
+                content_length: None,
+                headers: Headers::new(),
+            },
+        ];
+        let mut wire = Vec::new();
+        for response in responses {
+            response.write_to(&mut wire).unwrap();
+        }
+
+        let parsed = parse_wire(&wire);
+        let statuses: Vec<_> = parsed.iter().map(|response| response.status).collect();
+        assert_eq!(vec![404, 200, 304, 200, 200, 200, 200], statuses);
         assert_eq!(
-            Ok(String::from("./stuff.css")),
-            request.match_fetch("index.html")
+            vec![(String::from("Content-Length"), String::from("0"))],
+            parsed[0].fields
         );
+        assert_eq!(
+            vec![
+                (String::from("Content-Type"), String::from("text/plain")),
+                (String::from("X-Control"), String::from("a b c\td")),
+                (String::from("Content-Length"), String::from("9")),
+            ],
+            parsed[1].fields
+        );
+        assert_eq!(b"hello\r\n\r\n".to_vec(), parsed[1].content);
+        assert!(parsed[2].fields.is_empty() && parsed[2].content.is_empty());
+        assert_eq!(b"abc".to_vec(), parsed[3].content);
+        let mut content = b"chunk".to_vec();
+        content.extend_from_slice(&[b'x'; 300]);
+        assert_eq!(content, parsed[4].content);
+        assert_eq!(
+            vec![(String::from("Transfer-Encoding"), String::from("chunked"))],
+            parsed[4].fields
+        );
+        assert_eq!(
+            vec![(String::from("Content-Length"), String::from("5"))],
+            parsed[5].fields
+        );
+        assert_eq!(b"known".to_vec(), parsed[5].content);
+        assert_eq!(b"last".to_vec(), parsed[6].content);
+    }
 
-        request.fetch = String::from("../not_allow.png");
-        assert_eq!(forbidden_res, request.match_fetch("index.html"));
+    #[test]
+    fn writer_refuses_content_not_matching_its_length() {
+        let response = HttpResponse {
+            code: HttpResponseCode::OK200,
+            content: Some(Body::Bytes(b"abc".to_vec())),
+            content_length: Some(4),
+            headers: Headers::new(),
+        };
+        let mut wire = Vec::new();
+        let err = response.write_to(&mut wire).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        assert!(wire.is_empty());
 
-        request.fetch = String::from("/not//allow.jpg");
-        assert_eq!(forbidden_res, request.match_fetch("index.html"));
+        for (chunks, kind) in [
+            (vec![b"ab".to_vec(), b"cd".to_vec()], ErrorKind::InvalidData),
+            (vec![b"a".to_vec()], ErrorKind::UnexpectedEof),
+        ] {
+            let response = HttpResponse {
+                code: HttpResponseCode::OK200,
+                content: Some(Body::Chunks(Box::new(chunks.into_iter().map(Ok)))),
+                content_length: Some(3),
+                headers: Headers::new(),
+            };
+            let mut wire = Vec::new();
+            assert_eq!(kind, response.write_to(&mut wire).unwrap_err().kind());
+            // never more than announced
+            assert!(wire.ends_with(b"\r\n\r\nab") || wire.ends_with(b"\r\n\r\na"));
+        }
+    }
+
+    #[test]
+    fn responses_sent_over_sockets_parse_strictly() {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        std::fs::create_dir_all("target/qst-tests/wire").unwrap();
+        let content: Vec<u8> = (0..200_000).map(|i| (i % 253) as u8).collect();
+        std::fs::write("target/qst-tests/wire/file.bin", &content).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let sender = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let body = Body::file("target/qst-tests/wire/file.bin").unwrap();
+                let response = HttpResponse {
+                    code: HttpResponseCode::OK200,
+                    content_length: body.size().map(|size| size as usize),
+                    content: Some(body),
+                    headers: Headers::new(),
+                };
+                response.send_to(&server).unwrap();
+                HttpResponse::bad_request_400().send_to(&server).unwrap();
+            }
+        });
+        let mut wire = Vec::new();
+        (&client).read_to_end(&mut wire).unwrap();
+        sender.join().unwrap();
 
-        request.fetch = String::from("not_allow/");
-        assert_eq!(forbidden_res, request.match_fetch("index.html"));
+        let parsed = parse_wire(&wire);
+        assert_eq!(4, parsed.len());
+        assert_eq!(content, parsed[0].content);
+        assert_eq!(400, parsed[1].status);
+        assert_eq!(content, parsed[2].content);
     }
 }
@@ -1,105 +1,591 @@
+pub mod body;
+pub mod compress;
 pub mod config;
+pub mod date;
+pub mod error;
 pub mod http;
+pub mod listing;
+pub mod livereload;
+pub mod mime;
+pub mod mount;
+pub mod pool;
+pub mod proxy;
+pub mod range;
+pub mod shutdown;
+pub mod url;
+pub mod watch;
 
+use body::Body;
+use compress::Encoding;
 use config::Config;
+use error::ServerError;
 use http::*;
+use livereload::LiveReload;
+use mount::Resolved;
+use pool::{ThreadPool, DEFAULT_QUEUE_CAPACITY};
+use range::{ByteRange, RangeRequest};
+use shutdown::{ConnectionTracker, ShutdownHandle, ShutdownReport};
 use std::io::Result as IoResult;
 use std::{
     fs,
-    io::Write,
-    net::{TcpListener, TcpStream},
-    process, thread,
+    io::{BufRead, BufReader, ErrorKind},
+    iter,
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
 
-static mut THREAD_COUNT: isize = 0;
+/// How long the accept loop sleeps when no connection is pending before checking for a shutdown
+/// request again.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(5);
 
-fn write_tcp_or_bail_out(mut stream: TcpStream, string: String) {
-    stream.write_all(string.as_bytes()).unwrap_or_else(|_| {
-        eprintln!("Fatal server error: Cannot write to TCP Stream. Bailing out. You're on your own. Good luck.");
-        process::exit(1);
+/// How long a write may block on a client that stopped reading.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Methods files are served with. Proxied paths take any method the upstream does.
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// Writes the response to the stream, without its content if `head_only`, as the answer to a
+/// `HEAD`. Returns `false` if the write failed, in which case the connection must be dropped.
+/// Failures only concern this connection: clients cancel requests all the time, so they are
+/// logged and counted, unless the server cut the connection itself while shutting down.
+fn write_response(
+    stream: &TcpStream,
+    response: HttpResponse,
+    head_only: bool,
+    connections: &ConnectionTracker,
+) -> bool {
+    let sent = if head_only {
+        response.write_head_to(&mut &*stream)
+    } else {
+        response.send_to(stream)
+    };
+    match sent {
+        Ok(()) => {
+            connections.count_response();
+            true
+        }
+        Err(_) if connections.is_cut() => false,
+        Err(err) => {
+            connections.count_error();
+            let peer = match stream.peer_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => String::from("client"),
+            };
+            eprintln!("Cannot write to {peer}: {err}. Closing the connection.");
+            false
+        }
+    }
+}
+
+/// Builds the 404 response, with the 404 file of the config if there is one.
+fn not_found(config: &Config) -> HttpResponse {
+    let mut headers = Headers::new();
+    let (content, length) = match config.err404_file {
+        // if the file is valid, uses it, else fails silently
+        Some(ref file) => {
+            if let IoResult::Ok(bytes) = fs::read(file) {
+                let len = bytes.len();
+                let content_type = config.mime_types.content_type(file);
+                headers.insert("Content-Type", &content_type);
+                (Some(Body::Bytes(bytes)), Some(len))
+            } else {
+                (None, None)
+            }
+        }
+        None => (None, None),
+    };
+    HttpResponse {
+        code: HttpResponseCode::NotFound404,
+        content,
+        content_length: length,
+        headers,
+    }
+}
+
+/// Builds the 403 response.
+fn forbidden() -> HttpResponse {
+    HttpResponse {
+        code: HttpResponseCode::Forbbiden403,
+        content: None,
+        content_length: None,
+        headers: Headers::new(),
+    }
+}
+
+/// Response telling which methods files can be requested with, in `Allow`. Sent to `OPTIONS` and
+/// as a 405 to the other methods.
+fn allowed_methods(code: HttpResponseCode) -> HttpResponse {
+    let mut headers = Headers::new();
+    headers.insert("Allow", ALLOWED_METHODS);
+    HttpResponse {
+        code,
+        content: None,
+        content_length: None,
+        headers,
+    }
+}
+
+/// Builds the listing of the directory `dir`, served at `url_path`, as JSON if the client accepts
+/// it, else as HTML.
+fn list_directory(
+    request: &HttpRequest,
+    url_path: &str,
+    dir: &str,
+    config: &Config,
+) -> HttpResponse {
+    let entries = match listing::read_entries(Path::new(dir)) {
+        Ok(entries) => entries,
+        Err(_) => return not_found(config),
+    };
+    let wants_json = request.headers.get_list("Accept").any(|media_range| {
+        let media_type = media_range.split(';').next().unwrap_or("").trim();
+        media_type.eq_ignore_ascii_case("application/json")
     });
+    let (content, content_type) = if wants_json {
+        (listing::render_json(url_path, &entries), "application/json")
+    } else {
+        (
+            listing::render_html(url_path, &entries),
+            "text/html; charset=utf-8",
+        )
+    };
+
+    let mut headers = Headers::new();
+    headers.insert("Content-Type", content_type);
+    headers.insert("Vary", "Accept");
+    HttpResponse {
+        code: HttpResponseCode::OK200,
+        content_length: Some(content.len()),
+        content: Some(Body::Bytes(content.into_bytes())),
+        headers,
+    }
+}
+
+/// Finds the index file of the resolved directory `dir`, which ends with `/`: the default file or
+/// else the first of the fallbacks that exists and is allowed by the symlink policy.
+fn find_index(dir: &Resolved, config: &Config) -> Option<String> {
+    iter::once(&config.default_file)
+        .chain(&config.index_fallbacks)
+        .map(|name| format!("{}{name}", dir.path))
+        .find(|path| {
+            Path::new(path).is_file() && matches!(dir.allows(path, config.symlinks), Ok(true))
+        })
 }
 
-pub fn respond_http_request(
-    mut stream: TcpStream,
-    default_file: String,
-    err404_file: Option<String>,
+/// Builds the response to a single request.
+fn respond_to(request: &HttpRequest, config: &Config) -> HttpResponse {
+    match request.method {
+        HttpMethod::GET | HttpMethod::HEAD => (),
+        HttpMethod::OPTIONS => return allowed_methods(HttpResponseCode::OK200),
+        _ => return allowed_methods(HttpResponseCode::MethodNotAllowed405),
+    }
+
+    let fetch = match request.match_fetch() {
+        Ok(fetch) => fetch,
+        Err(response) => return response,
+    };
+
+    let mut count = fetch.chars();
+    count.next().unwrap(); // will never panic as fetch is always ./<stuff>
+    count.next().unwrap();
+    if count.next() == Some('_') {
+        return forbidden();
+    }
+
+    // the decoded URL path, without the leading dot
+    let url_path = &fetch[1..];
+    let mut resolved = match config.mounts.resolve(url_path) {
+        Some(resolved) => resolved,
+        None => return not_found(config),
+    };
+    // single-page applications route these paths themselves, from their default file
+    if config.spa_prefixes.contains(&resolved.prefix)
+        && !Path::new(&resolved.path).exists()
+        && Path::new(url_path).extension().is_none()
+    {
+        let fallback = Path::new(&resolved.dir).join(&config.default_file);
+        resolved.path = fallback.to_string_lossy().into_owned();
+    }
+    match resolved.allows(&resolved.path, config.symlinks) {
+        Ok(true) => (),
+        Ok(false) => return forbidden(),
+        Err(_) => return not_found(config),
+    }
+    let mut path = resolved.path.clone();
+
+    if path.ends_with('/') {
+        match find_index(&resolved, config) {
+            Some(index) => path = index,
+            None if !Path::new(&path).is_dir() => return not_found(config),
+            None if config.dir_listing => return list_directory(request, url_path, &path, config),
+            None => return forbidden(),
+        }
+    } else if Path::new(&path).is_dir() {
+        // relative links in the index are resolved against the directory only with a trailing /
+        let mut headers = Headers::new();
+        let mut location = request.raw_path().to_string();
+        if !location.starts_with('/') {
+            location.insert(0, '/');
+        }
+        location.push('/');
+        if let Some(query) = request.raw_query() {
+            location.push('?');
+            location.push_str(query);
+        }
+        headers.insert("Location", &location);
+        return HttpResponse {
+            code: HttpResponseCode::MovedPermanently301,
+            content: None,
+            content_length: None,
+            headers,
+        };
+    }
+
+    // precompressed variants built next to the file are sent as they are, with the type of the
+    // file. Validators and ranges then apply to the variant
+    let mut headers = Headers::new();
+    let content_type = config.mime_types.content_type(&path);
+    // live reload pages get a script, they are modified before any compression. Like compressed
+    // files, they are changed in memory, so larger pages are sent as they are
+    let inject = config.live_reload
+        && content_type.starts_with("text/html")
+        && fs::metadata(&path)
+            .is_ok_and(|metadata| metadata.len() <= compress::MAX_COMPRESSED_SIZE);
+    let mut coding = None;
+    if !inject {
+        let (sidecar, any) = find_sidecar(request, &resolved, &path, config);
+        if any {
+            headers.insert("Vary", "Accept-Encoding");
+        }
+        if let Some((sidecar, sidecar_coding)) = sidecar {
+            path = sidecar;
+            coding = Some(sidecar_coding);
+        }
+    }
+
+    // validators let clients revalidate their copy instead of downloading it again
+    let metadata =
+        fs::metadata(&path).and_then(|metadata| Ok((metadata.len(), metadata.modified()?)));
+    let mut encoding = None;
+    if let Ok((len, modified)) = metadata {
+        // the compressed variant is chosen first, as it has a validator of its own
+        let compressible = config.compression
+            && coding.is_none()
+            && mime::is_compressible(&content_type)
+            && (compress::MIN_COMPRESSED_SIZE..=compress::MAX_COMPRESSED_SIZE).contains(&len);
+        if compressible {
+            headers.insert("Vary", "Accept-Encoding");
+            // ranges are served from the file as it is on disk
+            if inject || !request.headers.contains("Range") {
+                encoding = negotiate_encoding(request);
+                coding = encoding.map(|encoding| encoding.name());
+            }
+        }
+        let mut etag = http::weak_etag(len, modified);
+        if inject {
+            etag = format!("{}-live\"", etag.trim_end_matches('"'));
+        }
+        if let Some(coding) = coding {
+            etag = format!("{}-{coding}\"", etag.trim_end_matches('"'));
+            headers.insert("Content-Encoding", coding);
+        }
+        headers.insert("ETag", &etag);
+        let last_modified = date::UtcDateTime::from_system_time(modified).to_http_date();
+        headers.insert("Last-Modified", &last_modified);
+        if !inject {
+            headers.insert("Accept-Ranges", "bytes");
+        }
+        if request.is_not_modified(&etag, modified) {
+            return HttpResponse {
+                code: HttpResponseCode::NotModified304,
+                content: None,
+                content_length: None,
+                headers,
+            };
+        }
+
+        let ranges = match request.headers.get("Range") {
+            Some(ranges) if !inject && request.if_range_matches(&etag, modified) => {
+                range::parse(ranges, len)
+            }
+            _ => RangeRequest::Whole,
+        };
+        match ranges {
+            RangeRequest::Whole => (),
+            RangeRequest::Unsatisfiable => {
+                headers.insert("Content-Range", &format!("bytes */{len}"));
+                return HttpResponse {
+                    code: HttpResponseCode::RangeNotSatisfiable416,
+                    content: None,
+                    content_length: None,
+                    headers,
+                };
+            }
+            RangeRequest::Partial(ranges) => {
+                return match partial_content(&path, &ranges, len, &content_type, headers) {
+                    Ok(response) => response,
+                    Err(_) => not_found(config),
+                };
+            }
+        }
+    }
+
+    if inject || encoding.is_some() {
+        // changed in memory, which the size limits keep reasonable
+        return match fs::read(&path) {
+            Ok(mut content) => {
+                if inject {
+                    content = livereload::inject_script(&content);
+                }
+                if let Some(encoding) = encoding {
+                    content = encoding.encode(&content);
+                }
+                headers.insert("Content-Type", &content_type);
+                HttpResponse {
+                    code: HttpResponseCode::OK200,
+                    content_length: Some(content.len()),
+                    content: Some(Body::Bytes(content)),
+                    headers,
+                }
+            }
+            Err(_) => not_found(config),
+        };
+    }
+
+    // actually send the file, byte by byte as it is on disk. It is streamed, never loaded whole
+    match Body::file(&path) {
+        Ok(body) => {
+            headers.insert("Content-Type", &content_type);
+            HttpResponse {
+                code: HttpResponseCode::OK200,
+                content_length: body.size().map(|size| size as usize),
+                content: Some(body),
+                headers,
+            }
+        }
+        Err(_) => not_found(config),
+    }
+}
+
+/// Looks for the precompressed variants of the file at `path`, built next to it as
+/// [`compress::SIDECARS`]. Returns the one to send along with its coding, and whether there is any
+/// variant at all, in which case the response varies on `Accept-Encoding`.
+fn find_sidecar(
+    request: &HttpRequest,
+    resolved: &Resolved,
+    path: &str,
+    config: &Config,
+) -> (Option<(String, &'static str)>, bool) {
+    let sidecars: Vec<(String, &'static str)> = compress::SIDECARS
+        .iter()
+        .map(|(extension, coding)| (format!("{path}.{extension}"), *coding))
+        .filter(|(sidecar, _)| {
+            Path::new(sidecar).is_file()
+                && resolved.allows(sidecar, config.symlinks).unwrap_or(false)
+        })
+        .collect();
+    let codings: Vec<&'static str> = sidecars.iter().map(|(_, coding)| *coding).collect();
+    let any = !sidecars.is_empty();
+    let chosen = compress::negotiate(request.headers.get_list("Accept-Encoding"), &codings)
+        .and_then(|chosen| sidecars.into_iter().find(|(_, coding)| *coding == chosen));
+    (chosen, any)
+}
+
+/// Picks the encoding to compress the response with, from the `Accept-Encoding` of `request`.
+fn negotiate_encoding(request: &HttpRequest) -> Option<Encoding> {
+    let names = compress::ENCODINGS.map(|encoding| encoding.name());
+    let name = compress::negotiate(request.headers.get_list("Accept-Encoding"), &names)?;
+    compress::ENCODINGS
+        .into_iter()
+        .find(|encoding| encoding.name() == name)
+}
+
+/// Builds the 206 response with the `ranges` of the file at `path`, of `total` bytes: the range
+/// itself if there is a single one, else a `multipart/byteranges` body.
+fn partial_content(
+    path: &str,
+    ranges: &[ByteRange],
+    total: u64,
+    content_type: &str,
+    mut headers: Headers,
+) -> IoResult<HttpResponse> {
+    let (content, size) = if let [range] = ranges {
+        headers.insert("Content-Type", content_type);
+        headers.insert("Content-Range", &range.content_range(total));
+        (
+            Body::file_range(path, range.first, range.size())?,
+            Some(range.size()),
+        )
+    } else {
+        let mut parts = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            parts.push((*range, Body::file_range(path, range.first, range.size())?));
+        }
+        let boundary = range::new_boundary();
+        let multipart_type = format!("multipart/byteranges; boundary={boundary}");
+        headers.insert("Content-Type", &multipart_type);
+        range::multipart_body(&boundary, content_type, total, parts)
+    };
+    Ok(HttpResponse {
+        code: HttpResponseCode::PartialContent206,
+        content_length: size.map(|size| size as usize),
+        content: Some(content),
+        headers,
+    })
+}
+
+/// Serves every request sent over a connection. Requests are read one after the other from the
+/// same buffer, so pipelined requests are answered in order. The connection is kept open while the
+/// client asks for it, up to `config.keep_alive_max` requests, and closed when idle for
+/// `config.keep_alive_timeout` seconds.
+pub fn respond_http_request(stream: TcpStream, config: &Config) {
+    serve_connection(stream, config, &Arc::default(), None);
+}
+
+fn serve_connection(
+    stream: TcpStream,
+    config: &Config,
+    connections: &Arc<ConnectionTracker>,
+    live_reload: Option<&LiveReload>,
 ) {
-    match HttpRequest::parse_tcp_stream(&mut stream) {
-        Ok(request) => {
-            match request.match_fetch(&default_file[..]) {
-                Ok(fetch) => {
-                    let mut count = fetch.chars();
-                    count.next().unwrap(); // will never panic as fetch is always ./<stuff>
-                    count.next().unwrap();
-                    if count.next().unwrap() == '_' {
-                        write_tcp_or_bail_out(
-                            stream,
-                            HttpResponse {
-                                code: HttpResponseCode::Forbbiden403,
-                                content: None,
-                                content_length: None,
-                            }
-                            .to_string(),
-                        );
-                        return;
-                    }
+    let connection = match connections.register(&stream) {
+        Some(connection) => connection,
+        None => return,
+    };
+    if stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_err() {
+        return;
+    }
+    // the head and a streamed body go out in separate writes, the body must not wait for an ACK
+    let _ = stream.set_nodelay(true);
+    let persistent = config.keep_alive_timeout > 0;
+    if persistent {
+        let timeout = Duration::from_secs(config.keep_alive_timeout);
+        if stream.set_read_timeout(Some(timeout)).is_err() {
+            return;
+        }
+    }
 
-                    // actually read the file and send it
-                    if let IoResult::Ok(content) = fs::read_to_string(fetch) {
-                        let len = content.len();
-                        write_tcp_or_bail_out(
-                            stream,
-                            HttpResponse {
-                                code: HttpResponseCode::OK200,
-                                content: Some(content),
-                                content_length: Some(len),
-                            }
-                            .to_string(),
-                        );
-                    } else {
-                        let (content, length) = match err404_file {
-                            // if the file is valid, uses it, else fails silently
-                            Some(file) => {
-                                if let IoResult::Ok(string) = fs::read_to_string(file) {
-                                    let len = string.len();
-                                    (Some(string), Some(len))
-                                } else {
-                                    (None, None)
-                                }
-                            }
-                            None => (None, None),
-                        };
-                        write_tcp_or_bail_out(
-                            stream,
-                            HttpResponse {
-                                code: HttpResponseCode::NotFound404,
-                                content,
-                                content_length: length,
-                            }
-                            .to_string(),
-                        );
-                    }
+    let mut reader = BufReader::new(&stream);
+    let mut served = 0;
+    loop {
+        // a shutdown closes idle connections, so they must be marked as such
+        if served > 0 && !connection.set_idle() {
+            return;
+        }
+        // the client may close an idle connection or let it time out, no response is due then
+        match reader.fill_buf() {
+            Ok(buffer) if !buffer.is_empty() => {}
+            _ => return,
+        }
+        connection.set_busy();
+        served += 1;
+
+        let request = HttpRequest::parse_from_lines_iterator((&mut reader).lines()).and_then(
+            |mut request| {
+                request.answer_expectation(&mut &stream, config.max_body_size)?;
+                request.read_body(&mut reader, config.max_body_size)?;
+                Ok(request)
+            },
+        );
+        let (mut response, keep_alive, head_only, events) = match request {
+            Ok(request) => {
+                // the response reaching the limit is the last one the server sends
+                let last = config
+                    .limit_requests
+                    .is_some_and(|limit| connections.responses() + 1 >= limit);
+                let keep_alive = persistent
+                    && !connections.is_draining()
+                    && served < config.keep_alive_max
+                    && !last
+                    && request.keep_alive();
+                let (version, method) = (request.version, request.method);
+                // proxied paths are matched once decoded and checked, like the ones on the disk
+                let route = request.match_fetch().ok().and_then(|fetch| {
+                    config
+                        .proxies
+                        .find(&fetch[1..], request.raw_path(), request.raw_query())
+                });
+                let events = live_reload.filter(|_| {
+                    request.raw_path() == livereload::EVENTS_PATH
+                        && matches!(method, HttpMethod::GET | HttpMethod::HEAD)
+                });
+                let mut response = match (events, route) {
+                    (Some(live_reload), _) => live_reload.events(),
+                    (_, Some(route)) => proxy::forward(request, &route, stream.peer_addr().ok()),
+                    (_, None) => respond_to(&request, config),
+                };
+                // content of unknown length goes out in chunks to the clients that understand them
+                if version == HttpVersion::Http11 && !response.is_delimited() {
+                    response.headers.insert("Transfer-Encoding", "chunked");
+                }
+                let head_only = method == HttpMethod::HEAD;
+                // an event stream is written by a thread of its own, which sends nothing after it
+                let events = events.filter(|_| !head_only);
+                (response, keep_alive && events.is_none(), head_only, events)
+            }
+            // after a malformed request or an unread body the stream can't be trusted anymore
+            Err(response) => (response, false, false, None),
+        };
+
+        // without a length or chunks, only closing the connection ends the content
+        let keep_alive = keep_alive && (head_only || response.is_delimited());
+        if keep_alive {
+            let timeout = format!("timeout={}", config.keep_alive_timeout);
+            response.headers.insert("Connection", "keep-alive");
+            response.headers.insert("Keep-Alive", &timeout);
+        } else {
+            response.headers.insert("Connection", "close");
+        }
+
+        if let Some(live_reload) = events {
+            // the thread tracks the connection itself, it may outlive this job
+            drop(connection);
+            let tracker = Arc::clone(connections);
+            let write_events = move || {
+                if let Some(_connection) = tracker.register(&stream) {
+                    write_response(&stream, response, false, &tracker);
                 }
-                Err(response) => write_tcp_or_bail_out(stream, response.to_string()),
+            };
+            if let Err(err) = live_reload.spawn_stream(write_events) {
+                connections.count_error();
+                eprintln!(
+                    "Cannot spawn a thread for an event stream: {err}. Closing the connection."
+                );
             }
+            return;
+        }
+
+        if !write_response(&stream, response, head_only, connections) || !keep_alive {
+            return;
         }
-        Err(response) => write_tcp_or_bail_out(stream, response.to_string()),
     }
 }
 
-/// Server main loop. Receives a config and a `incoming` function that must return
-/// `Result<Option<TcpStream>, String>`. The `incoming` must return:
+/// Server main loop. Connections are answered by a pool of at most `config.max_threads` reused
+/// workers. Receives a config and a `incoming` function that must return
+/// `Result<Option<TcpStream>, ServerError>`. The `incoming` must return:
 /// - `Ok<Some<TcpStream>>` to signal that a new `TcpStream` was received;
 /// - `Ok<None>` to signal the server must stop without errors;
-/// - `Err<String>` to signal the server must stop with a error.
+/// - `Err<ServerError>` to signal the server must stop with a error.
 ///
-/// Returns `Ok(())` in case of no errors, `Err<String>` in case of errors within the `incoming`
-/// function.
+/// With `config.live_reload`, the served directories are watched for as long as the server runs
+/// and [`livereload::EVENTS_PATH`] streams their changes. Each event stream is written by a thread
+/// of its own, outside of `config.max_threads`.
+///
+/// Once `incoming` stops the server, idle persistent connections are closed and the ones in flight
+/// get up to `config.shutdown_timeout` seconds to finish. Connections still open after that are
+/// cut off.
+///
+/// Returns a [`ShutdownReport`] in case of no errors, `Err<ServerError>` in case of errors within
+/// the `incoming` function or if no thread could be spawned.
 ///
 /// # Examples
 /// ```
 /// use qst::config::Config;
+/// use qst::error::ServerError;
 /// use qst::serve;
 /// use std::net::{TcpStream, TcpListener};
 ///
@@ -110,113 +596,456 @@ pub fn respond_http_request(
 /// serve(config, ||{
 ///     match incoming_iter.next() {
 ///         Some(result_stream) => match result_stream {
-///             Err(_) => Err(String::from("Connection failed. Bailing out.")),
+///             Err(err) => Err(ServerError::Accept(err)),
 ///             Ok(stream) => Ok(Some(stream)),
 ///         },
 ///         None => Ok(None),
 ///     }
 /// });
 /// ```
-pub fn serve<F>(config: Config, mut incoming: F) -> Result<(), String>
+pub fn serve<F>(config: Config, incoming: F) -> Result<ShutdownReport, ServerError>
 where
-    F: FnMut() -> Result<Option<TcpStream>, String>,
+    F: FnMut() -> Result<Option<TcpStream>, ServerError>,
 {
-    loop {
+    serve_tracked(config, Arc::new(ConnectionTracker::default()), incoming)
+}
+
+/// [`serve`], keeping track of the connections with `connections`.
+fn serve_tracked<F>(
+    config: Config,
+    connections: Arc<ConnectionTracker>,
+    mut incoming: F,
+) -> Result<ShutdownReport, ServerError>
+where
+    F: FnMut() -> Result<Option<TcpStream>, ServerError>,
+{
+    let config = Arc::new(config);
+    let live_reload = config
+        .live_reload
+        .then(|| LiveReload::watch(&config.mounts));
+    let mut pool = ThreadPool::new(config.max_threads, DEFAULT_QUEUE_CAPACITY);
+    let result = loop {
         match incoming() {
             Ok(Some(stream)) => {
-                // wait the thread counter
-                if let Some(max_threads) = config.max_threads {
-                    unsafe {
-                        loop {
-                            if THREAD_COUNT < max_threads as isize {
-                                break;
-                            }
-                            thread::yield_now();
-                        }
-                    }
+                let config = Arc::clone(&config);
+                let connections = Arc::clone(&connections);
+                let live_reload = live_reload.clone();
+                let job =
+                    move || serve_connection(stream, &config, &connections, live_reload.as_deref());
+                if let Err(err) = pool.execute(job) {
+                    break Err(ServerError::Spawn(err));
                 }
+            }
+            Ok(None) => break Ok(()),
+            Err(msg) => break Err(msg),
+        };
+    };
 
-                unsafe {
-                    THREAD_COUNT += 1;
-                }
+    // drain the connections in flight, event streams would never end on their own
+    connections.start_draining();
+    if let Some(live_reload) = &live_reload {
+        live_reload.close();
+    }
+    let deadline = Instant::now() + Duration::from_secs(config.shutdown_timeout);
+    let mut in_time = pool.wait_idle(deadline.saturating_duration_since(Instant::now()));
+    // the jobs of the pool start the event streams, they can't start new ones anymore
+    if let Some(live_reload) = &live_reload {
+        in_time =
+            in_time && live_reload.wait_streams(deadline.saturating_duration_since(Instant::now()));
+    }
+    if !in_time {
+        connections.cut_all();
+    }
+    pool.shutdown();
 
-                let default_file = config.default_file.clone();
-                let err404_file = match config.err404_file {
-                    Some(ref file) => Some(file.clone()),
-                    None => None,
-                };
+    let cut_off = connections.cut_off();
+    result.map(|()| ShutdownReport {
+        completed: pool.stats().completed - cut_off as u64,
+        cut_off,
+        connection_errors: connections.errors(),
+    })
+}
 
-                if thread::Builder::new()
-                    .spawn(move || {
-                        respond_http_request(stream, default_file, err404_file);
-                        unsafe {
-                            THREAD_COUNT -= 1;
-                        }
-                    })
-                    .is_err()
-                {
-                    return Err(String::from("ERROR: Unable to spawn new threads."));
-                }
+/// A server bound to its address and ready to run.
+///
+/// # Examples
+/// ```
+/// use qst::config::Config;
+/// use qst::Server;
+/// use std::thread;
+///
+/// let mut config = Config::new();
+/// config.port = String::from("0");
+/// let server = Server::bind(config).unwrap();
+/// let handle = server.shutdown_handle();
+/// let running = thread::spawn(move || server.run());
+///
+/// handle.shutdown();
+/// let report = running.join().unwrap().unwrap();
+/// assert_eq!(0, report.cut_off);
+/// ```
+pub struct Server {
+    config: Config,
+    listener: TcpListener,
+    handle: ShutdownHandle,
+}
+
+impl Server {
+    /// Binds to the address and port of the config. Returns Err(ServerError) in case of error.
+    pub fn bind(config: Config) -> Result<Server, ServerError> {
+        let full_addr = format!("{}:{}", config.addr, config.port);
+
+        let listener = match TcpListener::bind(&full_addr) {
+            IoResult::Ok(listener) => listener,
+            IoResult::Err(source) => {
+                return Err(ServerError::Bind {
+                    addr: full_addr,
+                    source,
+                })
             }
-            Ok(None) => return Ok(()),
-            Err(msg) => return Err(msg),
         };
+
+        Ok(Server {
+            config,
+            listener,
+            handle: ShutdownHandle::new(),
+        })
     }
-}
 
-/// Starts a server with a config. Returns Err(String) in case of error.
-pub fn start_server(config: Config) -> Result<(), String> {
-    let full_addr = format!("{}:{}", config.addr, config.port);
+    /// The address the server is bound to. Useful when binding to port 0.
+    pub fn local_addr(&self) -> IoResult<SocketAddr> {
+        self.listener.local_addr()
+    }
 
-    let listener = match TcpListener::bind(full_addr) {
-        IoResult::Ok(listener) => listener,
-        IoResult::Err(msg) => {
-            // full_addr was moved to TcpListener::bind
-            let msg = format!("Unable to bind to {}:{}: {msg}", config.addr, config.port);
-            return Err(msg);
-        }
-    };
+    /// Returns a handle that stops the server when asked to.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.handle.clone()
+    }
 
-    println!(
-        "Serving HTTP on {} port {} (http://{}:{})...",
-        config.addr, config.port, config.addr, config.port
-    );
+    /// Runs the server until the shutdown handle is used or `config.limit_requests` responses
+    /// were sent. See [`serve`].
+    pub fn run(self) -> Result<ShutdownReport, ServerError> {
+        let Server {
+            config,
+            listener,
+            handle,
+        } = self;
+        if let Err(err) = listener.set_nonblocking(true) {
+            return Err(ServerError::Listener(err));
+        }
 
-    let mut iter = listener.incoming();
-    if let Some(limit) = config.limit_requests {
-        let mut count = 0;
-        serve(config, move || {
-            if count < limit {
-                count += 1;
-                match iter.next() {
-                    Some(result_stream) => match result_stream {
-                        Err(_) => Err(String::from("Connection failed. Bailing out.")),
-                        Ok(stream) => Ok(Some(stream)),
-                    },
-                    None => Ok(None),
+        let limit = config.limit_requests;
+        let connections = Arc::new(ConnectionTracker::default());
+        let tracker = Arc::clone(&connections);
+        serve_tracked(config, connections, move || loop {
+            if handle.is_requested() || limit.is_some_and(|limit| tracker.responses() >= limit) {
+                return Ok(None);
+            }
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    // some platforms make accepted streams inherit the non-blocking mode
+                    if stream.set_nonblocking(false).is_ok() {
+                        return Ok(Some(stream));
+                    }
                 }
-            } else {
-                Ok(None)
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL)
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(ServerError::Accept(err)),
             }
         })
-    } else {
-        serve(config, move || match iter.next() {
-            Some(result_stream) => match result_stream {
-                Err(_) => Err(String::from("Connection failed. Bailing out.")),
-                Ok(stream) => Ok(Some(stream)),
-            },
-            None => Ok(None),
-        })
     }
 }
 
+/// Starts a server with a config, until SIGINT or SIGTERM is received. Returns Err(ServerError) in
+/// case of error.
+pub fn start_server(config: Config) -> Result<ShutdownReport, ServerError> {
+    let server = Server::bind(config)?;
+
+    println!(
+        "Serving HTTP on {} port {} (http://{}:{})...",
+        server.config.addr, server.config.port, server.config.addr, server.config.port
+    );
+
+    server.shutdown_handle().listen_for_signals();
+    server.run()
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
-    use std::time::Duration;
+    use mount::SymlinkPolicy;
+    use std::io::{Read, Write};
+    use std::time::{Duration, Instant};
     use std::{sync::mpsc, thread};
 
+    /// Answers a single connection with `config` and returns the client side of it.
+    fn connect_to_responder(config: Config) -> (TcpStream, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            respond_http_request(stream, &config);
+        });
+        (TcpStream::connect(addr).unwrap(), responder)
+    }
+
+    /// Answers a GET of `fetch` sent with the fields `headers`, as configured by `config`.
+    fn get(fetch: &str, headers: &[(&str, &str)], config: &Config) -> HttpResponse {
+        let mut lines = vec![format!("GET {fetch} HTTP/1.1")];
+        lines.extend(
+            headers
+                .iter()
+                .map(|(name, value)| format!("{name}: {value}")),
+        );
+        let request =
+            HttpRequest::parse_from_lines_iterator(lines.into_iter().map(IoResult::Ok)).unwrap();
+        respond_to(&request, config)
+    }
+
+    /// Reads the content of a response, streamed or not.
+    fn content(response: &mut HttpResponse) -> Option<Vec<u8>> {
+        response
+            .content
+            .take()
+            .map(|body| body.into_bytes().unwrap())
+    }
+
+    /// Reads the status line and the headers of a response.
+    fn read_head(reader: &mut impl BufRead) -> (String, Headers) {
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        let mut headers = Headers::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end_matches("\r\n");
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(": ").unwrap();
+            headers.append(name, value);
+        }
+        (status.trim_end().to_string(), headers)
+    }
+
+    /// Reads one response framed by its Content-Length, if any. Returns the status line, the headers
+    /// and the body.
+    fn read_response(reader: &mut impl BufRead) -> (String, Headers, Vec<u8>) {
+        let (status, headers) = read_head(reader);
+        let length = headers
+            .get("Content-Length")
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        (status, headers, body)
+    }
+
+    #[test]
+    fn connection_answers_pipelined_requests_in_order() {
+        let (mut client, responder) = connect_to_responder(Config::new());
+        client
+            .write_all(
+                b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                GET /Cargo.toml HTTP/1.1\r\n\r\n\
+                GET /missing.js HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut reader = BufReader::new(client);
+
+        let (status, headers, body) = read_response(&mut reader);
+        assert_eq!("HTTP/1.1 200 Ok", status);
+        assert_eq!(Some("keep-alive"), headers.get("Connection"));
+        assert_eq!(fs::read("index.html").unwrap(), body);
+
+        let (status, _, body) = read_response(&mut reader);
+        assert_eq!("HTTP/1.1 200 Ok", status);
+        assert_eq!(fs::read("Cargo.toml").unwrap(), body);
+
+        let (status, headers, body) = read_response(&mut reader);
+        assert_eq!("HTTP/1.1 404 Not Found", status);
+        assert_eq!(Some("close"), headers.get("Connection"));
+        assert!(body.is_empty());
+
+        // nothing may follow the last response
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn other_methods_are_told_what_is_allowed() {
+        let mut config = Config::new();
+        config.max_body_size = 8;
+        let (mut client, responder) = connect_to_responder(config);
+        client
+            .write_all(
+                b"OPTIONS * HTTP/1.1\r\n\r\n\
+                POST /index.html HTTP/1.1\r\nContent-Length: 4\r\n\r\nabcd\
+                DELETE /index.html HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n0\r\n\r\n\
+                PUT /index.html HTTP/1.1\r\nContent-Length: 9\r\n\r\n",
+            )
+            .unwrap();
+        let mut reader = BufReader::new(client);
+
+        let (status, headers, body) = read_response(&mut reader);
+        assert_eq!("HTTP/1.1 200 Ok", status);
+        assert_eq!(Some("GET, HEAD, OPTIONS"), headers.get("Allow"));
+        assert_eq!(Some("0"), headers.get("Content-Length"));
+        assert!(body.is_empty());
+
+        // the bodies are read, so the connection goes on
+        for _ in 0..2 {
+            let (status, headers, _) = read_response(&mut reader);
+            assert_eq!("HTTP/1.1 405 Method Not Allowed", status);
+            assert_eq!(Some("GET, HEAD, OPTIONS"), headers.get("Allow"));
+            assert_eq!(Some("keep-alive"), headers.get("Connection"));
+        }
+
+        let (status, headers, _) = read_response(&mut reader);
+        assert_eq!("HTTP/1.1 413 Content Too Large", status);
+        assert_eq!(Some("close"), headers.get("Connection"));
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn bodies_are_only_sent_after_a_100_continue() {
+        let mut config = Config::new();
+        config.max_body_size = 8;
+        let (client, responder) = connect_to_responder(config);
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        (&client)
+            .write_all(
+                b"POST /index.html HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 4\r\n\r\n",
+            )
+            .unwrap();
+
+        // the client waits for the interim response before sending the body
+        let mut interim = String::new();
+        while !interim.ends_with("\r\n\r\n") {
+            reader.read_line(&mut interim).unwrap();
+        }
+        assert_eq!("HTTP/1.1 100 Continue\r\n\r\n", interim);
+        (&client).write_all(b"abcd").unwrap();
+        let (status, headers, _) = read_response(&mut reader);
+        assert_eq!("HTTP/1.1 405 Method Not Allowed", status);
+        assert_eq!(Some("keep-alive"), headers.get("Connection"));
+
+        // a body over the limit is refused before it is sent
+        (&client)
+            .write_all(
+                b"PUT /index.html HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 9\r\n\r\n",
+            )
+            .unwrap();
+        let (status, headers, _) = read_response(&mut reader);
+        assert_eq!("HTTP/1.1 413 Content Too Large", status);
+        assert_eq!(Some("close"), headers.get("Connection"));
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn head_requests_get_the_headers_of_get_without_the_content() {
+        let (mut client, responder) = connect_to_responder(Config::new());
+        client
+            .write_all(
+                b"HEAD /index.html HTTP/1.1\r\n\r\n\
+                GET /index.html HTTP/1.1\r\n\r\n\
+                HEAD /Cargo.toml HTTP/1.1\r\nRange: bytes=0-9\r\n\r\n\
+                HEAD /README.md HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n\
+                HEAD /missing.js HTTP/1.1\r\n\r\n\
+                HEAD /index.html HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut reader = BufReader::new(client);
+
+        let (head_status, head_headers) = read_head(&mut reader);
+        let (status, headers, body) = read_response(&mut reader);
+        assert_eq!("HTTP/1.1 200 Ok", head_status);
+        assert_eq!(status, head_status);
+        let length = fs::metadata("index.html").unwrap().len().to_string();
+        assert_eq!(Some(&length[..]), head_headers.get("Content-Length"));
+        for name in [
+            "Content-Length",
+            "Content-Type",
+            "ETag",
+            "Last-Modified",
+            "Accept-Ranges",
+            "Connection",
+        ] {
+            assert!(head_headers.contains(name), "{name}");
+            assert_eq!(headers.get(name), head_headers.get(name), "{name}");
+        }
+        assert_eq!(fs::read("index.html").unwrap(), body);
+
+        let (status, headers) = read_head(&mut reader);
+        assert_eq!("HTTP/1.1 206 Partial Content", status);
+        assert_eq!(Some("10"), headers.get("Content-Length"));
+        assert!(headers.contains("Content-Range"));
+
+        let (status, headers) = read_head(&mut reader);
+        assert_eq!("HTTP/1.1 200 Ok", status);
+        assert_eq!(Some("gzip"), headers.get("Content-Encoding"));
+        let length: u64 = headers.get("Content-Length").unwrap().parse().unwrap();
+        assert!(length < fs::metadata("README.md").unwrap().len());
+
+        let (status, headers) = read_head(&mut reader);
+        assert_eq!("HTTP/1.1 404 Not Found", status);
+        assert_eq!(Some("keep-alive"), headers.get("Connection"));
+
+        let (status, headers) = read_head(&mut reader);
+        assert_eq!("HTTP/1.1 200 Ok", status);
+        assert_eq!(Some("close"), headers.get("Connection"));
+
+        // no content follows any of the heads
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn connection_closes_after_max_requests() {
+        let mut config = Config::new();
+        config.keep_alive_max = 2;
+        let (mut client, responder) = connect_to_responder(config);
+        client
+            .write_all(b"GET /index.html HTTP/1.1\r\n\r\nGET /index.html HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(client);
+
+        let (_, headers, _) = read_response(&mut reader);
+        assert_eq!(Some("keep-alive"), headers.get("Connection"));
+        let (_, headers, _) = read_response(&mut reader);
+        assert_eq!(Some("close"), headers.get("Connection"));
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn connection_closes_when_idle() {
+        let mut config = Config::new();
+        config.keep_alive_timeout = 1;
+        let (mut client, responder) = connect_to_responder(config);
+        client
+            .write_all(b"GET /index.html HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(client);
+        let start = Instant::now();
+
+        let (_, headers, _) = read_response(&mut reader);
+        assert_eq!(Some("timeout=1"), headers.get("Keep-Alive"));
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(900));
+        responder.join().unwrap();
+    }
+
     #[test]
     fn server_starts_and_quit_with_limit_0() {
         let mut config = Config::new();
@@ -226,7 +1055,7 @@ mod tests {
 
         thread::spawn(move || {
             match start_server(config) {
-                Ok(()) => tx.send(Ok(())).unwrap(),
+                Ok(_) => tx.send(Ok(())).unwrap(),
                 Err(msg) => tx.send(Err(msg)).unwrap(),
             };
         });
@@ -241,4 +1070,907 @@ mod tests {
         }
         panic!("Server did not stop within the 5 second timeout.")
     }
+
+    /// Binds a server to a random port and runs it on another thread.
+    fn run_server(
+        config: Config,
+    ) -> (
+        SocketAddr,
+        ShutdownHandle,
+        thread::JoinHandle<Result<ShutdownReport, ServerError>>,
+    ) {
+        let mut config = config;
+        config.port = String::from("0");
+        let server = Server::bind(config).unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        (addr, handle, thread::spawn(move || server.run()))
+    }
+
+    #[test]
+    fn server_shuts_down_idle_connections_right_away() {
+        let (addr, handle, running) = run_server(Config::new());
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /index.html HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(client);
+        let (_, headers, _) = read_response(&mut reader);
+        assert_eq!(Some("keep-alive"), headers.get("Connection"));
+
+        // the idle connection would stay open for 5 seconds otherwise
+        let start = Instant::now();
+        handle.shutdown();
+        let report = running.join().unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(
+            ShutdownReport {
+                completed: 1,
+                cut_off: 0,
+                connection_errors: 0,
+            },
+            report
+        );
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn server_cuts_off_connections_at_the_deadline() {
+        let mut config = Config::new();
+        config.shutdown_timeout = 0;
+        let (addr, handle, running) = run_server(config);
+
+        // a request that never ends keeps its connection busy
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /index.html HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        handle.shutdown();
+        let report = running.join().unwrap().unwrap();
+        assert_eq!(1, report.cut_off);
+        assert_eq!(0, report.completed);
+    }
+
+    #[test]
+    fn server_stops_after_limit_with_responses_sent() {
+        let mut config = Config::new();
+        config.limit_requests = Some(1);
+        let (addr, _, running) = run_server(config);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /index.html HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let (status, _, _) = read_response(&mut BufReader::new(client));
+        assert_eq!("HTTP/1.1 200 Ok", status);
+        assert_eq!(0, running.join().unwrap().unwrap().cut_off);
+    }
+
+    #[test]
+    fn server_limit_counts_the_requests_of_kept_alive_connections() {
+        let mut config = Config::new();
+        config.limit_requests = Some(2);
+        let (addr, _, running) = run_server(config);
+
+        let client = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        (&client)
+            .write_all(b"GET /index.html HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let (_, headers, _) = read_response(&mut reader);
+        assert_eq!(Some("keep-alive"), headers.get("Connection"));
+        (&client)
+            .write_all(b"GET /index.html HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let (status, headers, _) = read_response(&mut reader);
+        assert_eq!("HTTP/1.1 200 Ok", status);
+        assert_eq!(Some("close"), headers.get("Connection"));
+
+        let report = running.join().unwrap().unwrap();
+        assert_eq!(1, report.completed);
+        assert_eq!(0, report.cut_off);
+    }
+
+    #[test]
+    fn server_survives_clients_leaving_mid_response() {
+        fs::create_dir_all("target/qst-tests").unwrap();
+        fs::write("target/qst-tests/big.bin", vec![b'x'; 16 << 20]).unwrap();
+        let (addr, handle, running) = run_server(Config::new());
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /target/qst-tests/big.bin HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut start = [0; 1024];
+        client.read_exact(&mut start).unwrap();
+        drop(client);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /index.html HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let (status, _, _) = read_response(&mut BufReader::new(client));
+        assert_eq!("HTTP/1.1 200 Ok", status);
+
+        handle.shutdown();
+        let report = running.join().unwrap().unwrap();
+        assert_eq!(1, report.connection_errors);
+        assert_eq!(2, report.completed);
+    }
+
+    #[test]
+    fn directories_are_listed_only_when_enabled() {
+        fs::create_dir_all("target/qst-tests/listed").unwrap();
+        fs::write("target/qst-tests/listed/app.js", "app").unwrap();
+        fs::write("target/qst-tests/listed/_secret.js", "secret").unwrap();
+        let request = |accept: &str| {
+            let lines = [
+                String::from("GET /target/qst-tests/listed/ HTTP/1.1"),
+                format!("Accept: {accept}"),
+            ];
+            HttpRequest::parse_from_lines_iterator(lines.into_iter().map(IoResult::Ok)).unwrap()
+        };
+
+        let response = respond_to(&request("*/*"), &Config::new());
+        assert_eq!(HttpResponseCode::Forbbiden403, response.code);
+
+        let mut config = Config::new();
+        config.dir_listing = true;
+        let mut response = respond_to(&request("text/html,*/*;q=0.8"), &config);
+        assert_eq!(HttpResponseCode::OK200, response.code);
+        assert_eq!(
+            Some("text/html; charset=utf-8"),
+            response.headers.get("Content-Type")
+        );
+        let html = String::from_utf8(content(&mut response).unwrap()).unwrap();
+        assert!(html.contains("<a href=\"./app.js\">app.js</a>"));
+        assert!(!html.contains("_secret.js"));
+
+        let mut response = respond_to(&request("application/json"), &config);
+        assert_eq!(
+            Some("application/json"),
+            response.headers.get("Content-Type")
+        );
+        let json = String::from_utf8(content(&mut response).unwrap()).unwrap();
+        assert!(json.starts_with(
+            "{\"path\":\"/target/qst-tests/listed/\",\"entries\":[{\"name\":\"app.js\""
+        ));
+
+        // only the first segment is refused, deeper entries are just left out of the listings
+        let response = get("/_qst/app.js", &[], &config);
+        assert_eq!(HttpResponseCode::Forbbiden403, response.code);
+        let mut response = get("/target/qst-tests/listed/_secret.js", &[], &config);
+        assert_eq!(Some(b"secret".to_vec()), content(&mut response));
+    }
+
+    #[test]
+    fn directories_resolve_their_index_file() {
+        fs::create_dir_all("target/qst-tests/site/docs").unwrap();
+        fs::create_dir_all("target/qst-tests/site/notes").unwrap();
+        fs::write("target/qst-tests/site/docs/index.html", "docs").unwrap();
+        fs::write("target/qst-tests/site/notes/README.md", "notes").unwrap();
+        let mut config = Config::new();
+
+        let mut response = get("/target/qst-tests/site/docs/", &[], &config);
+        assert_eq!(HttpResponseCode::OK200, response.code);
+        assert_eq!(Some(b"docs".to_vec()), content(&mut response));
+
+        let response = get("/target/qst-tests/site/docs", &[], &config);
+        assert_eq!(HttpResponseCode::MovedPermanently301, response.code);
+        assert_eq!(
+            Some("/target/qst-tests/site/docs/"),
+            response.headers.get("Location")
+        );
+
+        let mut response = get("/target/qst-tests/site/notes/", &[], &config);
+        assert_eq!(Some(b"notes".to_vec()), content(&mut response));
+        assert_eq!(
+            Some("text/markdown; charset=utf-8"),
+            response.headers.get("Content-Type")
+        );
+        config.index_fallbacks.clear();
+        let response = get("/target/qst-tests/site/notes/", &[], &config);
+        assert_eq!(HttpResponseCode::Forbbiden403, response.code);
+
+        let response = get("/target/qst-tests/site/missing/", &[], &config);
+        assert_eq!(HttpResponseCode::NotFound404, response.code);
+    }
+
+    #[test]
+    fn encoded_fetches_and_queries_resolve_files() {
+        fs::create_dir_all("target/qst-tests/encoded/a dir").unwrap();
+        fs::write("target/qst-tests/encoded/café #1.js", "cafe").unwrap();
+
+        let config = Config::new();
+        let mut response = get(
+            "/target/qst-tests/encoded/caf%C3%A9%20%231.js?v=3",
+            &[],
+            &config,
+        );
+        assert_eq!(HttpResponseCode::OK200, response.code);
+        assert_eq!(Some(b"cafe".to_vec()), content(&mut response));
+
+        let response = get("/target/qst-tests/encoded/a%20dir?v=3", &[], &config);
+        assert_eq!(HttpResponseCode::MovedPermanently301, response.code);
+        assert_eq!(
+            Some("/target/qst-tests/encoded/a%20dir/?v=3"),
+            response.headers.get("Location")
+        );
+
+        let response = get(
+            "/target/qst-tests/encoded/%2e%2e/encoded/caf%C3%A9%20%231.js",
+            &[],
+            &config,
+        );
+        assert_eq!(HttpResponseCode::Forbbiden403, response.code);
+    }
+
+    #[test]
+    fn mounts_serve_their_own_directories() {
+        fs::create_dir_all("target/qst-tests/mounts/dist/css").unwrap();
+        fs::create_dir_all("target/qst-tests/mounts/shared/assets").unwrap();
+        fs::write("target/qst-tests/mounts/dist/index.html", "home").unwrap();
+        fs::write("target/qst-tests/mounts/dist/css/a.css", "css").unwrap();
+        fs::write("target/qst-tests/mounts/shared/assets/logo.svg", "svg").unwrap();
+        fs::write("target/qst-tests/mounts/secret.txt", "secret").unwrap();
+        let mut config = Config::new();
+        config.mounts.set_root("target/qst-tests/mounts/dist");
+        config
+            .mounts
+            .insert("/assets", "target/qst-tests/mounts/shared/assets/");
+
+        assert_eq!(Some(b"home".to_vec()), content(&mut get("/", &[], &config)));
+        assert_eq!(
+            Some(b"css".to_vec()),
+            content(&mut get("/css/a.css", &[], &config))
+        );
+        assert_eq!(
+            Some(b"svg".to_vec()),
+            content(&mut get("/assets/logo.svg", &[], &config))
+        );
+        assert_eq!(
+            Some("image/svg+xml; charset=utf-8"),
+            get("/assets/logo.svg", &[], &config)
+                .headers
+                .get("Content-Type")
+        );
+        assert_eq!(
+            Some("/assets/"),
+            get("/assets", &[], &config).headers.get("Location")
+        );
+        assert_eq!(
+            HttpResponseCode::NotFound404,
+            get("/Cargo.toml", &[], &config).code
+        );
+        assert_eq!(
+            HttpResponseCode::NotFound404,
+            get("/shared/assets/logo.svg", &[], &config).code
+        );
+        for fetch in [
+            "/../secret.txt",
+            "/assets/../../secret.txt",
+            "/assets/%2e%2e/logo.svg",
+        ] {
+            assert_eq!(
+                HttpResponseCode::Forbbiden403,
+                get(fetch, &[], &config).code
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolved_paths_stay_within_the_root() {
+        use std::os::unix::fs::symlink;
+        let base = Path::new("target/qst-tests/contained");
+        let _ = fs::remove_dir_all(base);
+        fs::create_dir_all(base.join("root/linked")).unwrap();
+        fs::write(base.join("root/foo..bar.js"), "dots").unwrap();
+        fs::write(base.join("secret.html"), "secret").unwrap();
+        symlink("../../secret.html", base.join("root/linked/index.html")).unwrap();
+        symlink("../secret.html", base.join("root/secret.html")).unwrap();
+        let mut config = Config::new();
+        config.mounts.set_root("target/qst-tests/contained/root");
+
+        assert_eq!(
+            Some(b"dots".to_vec()),
+            content(&mut get("/foo..bar.js", &[], &config))
+        );
+        assert_eq!(
+            HttpResponseCode::Forbbiden403,
+            get("/secret.html", &[], &config).code
+        );
+        // the escaping index is skipped, as if it didn't exist
+        assert_eq!(
+            HttpResponseCode::Forbbiden403,
+            get("/linked/", &[], &config).code
+        );
+        assert_eq!(
+            HttpResponseCode::Forbbiden403,
+            get("/linked/%2e%2e/%2e%2e/secret.html", &[], &config).code
+        );
+
+        config.symlinks = SymlinkPolicy::Follow;
+        assert_eq!(
+            Some(b"secret".to_vec()),
+            content(&mut get("/linked/", &[], &config))
+        );
+        assert_eq!(
+            Some(b"secret".to_vec()),
+            content(&mut get("/secret.html", &[], &config))
+        );
+    }
+
+    #[test]
+    fn connection_answers_conditional_requests_with_304() {
+        fs::create_dir_all("target/qst-tests/conditional").unwrap();
+        fs::write("target/qst-tests/conditional/app.js", "let a = 1;").unwrap();
+        let (mut client, responder) = connect_to_responder(Config::new());
+        client
+            .write_all(b"GET /target/qst-tests/conditional/app.js HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let (status, headers, body) = read_response(&mut reader);
+        assert_eq!("HTTP/1.1 200 Ok", status);
+        assert_eq!(b"let a = 1;".to_vec(), body);
+        let etag = headers.get("ETag").unwrap().to_string();
+        let last_modified = headers.get("Last-Modified").unwrap().to_string();
+        assert!(etag.starts_with("W/\""));
+        assert!(last_modified.ends_with(" GMT"));
+
+        for (name, value) in [
+            ("If-None-Match", &etag),
+            ("If-Modified-Since", &last_modified),
+        ] {
+            let request = format!(
+                "GET /target/qst-tests/conditional/app.js HTTP/1.1\r\n{name}: {value}\r\n\r\n"
+            );
+            client.write_all(request.as_bytes()).unwrap();
+            let (status, headers, body) = read_response(&mut reader);
+            assert_eq!("HTTP/1.1 304 Not Modified", status);
+            assert_eq!(None, headers.get("Content-Length"));
+            assert_eq!(Some(&etag[..]), headers.get("ETag"));
+            assert_eq!(Some("keep-alive"), headers.get("Connection"));
+            assert!(body.is_empty());
+        }
+
+        client
+            .write_all(
+                b"GET /target/qst-tests/conditional/app.js HTTP/1.1\r\n\
+                If-None-Match: W/\"stale\"\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let (status, _, body) = read_response(&mut reader);
+        assert_eq!("HTTP/1.1 200 Ok", status);
+        assert_eq!(b"let a = 1;".to_vec(), body);
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn files_are_served_by_ranges() {
+        fs::create_dir_all("target/qst-tests/ranges").unwrap();
+        fs::write(
+            "target/qst-tests/ranges/alphabet.txt",
+            "abcdefghijklmnopqrstuvwxyz",
+        )
+        .unwrap();
+
+        let alphabet = "/target/qst-tests/ranges/alphabet.txt";
+        let config = Config::new();
+        let response = get(alphabet, &[], &config);
+        assert_eq!(HttpResponseCode::OK200, response.code);
+        assert_eq!(Some("bytes"), response.headers.get("Accept-Ranges"));
+        let last_modified = response.headers.get("Last-Modified").unwrap().to_string();
+
+        let mut response = get(alphabet, &[("Range", "bytes=-3")], &config);
+        assert_eq!(HttpResponseCode::PartialContent206, response.code);
+        assert_eq!(Some(b"xyz".to_vec()), content(&mut response));
+        assert_eq!(Some(3), response.content_length);
+        assert_eq!(
+            Some("bytes 23-25/26"),
+            response.headers.get("Content-Range")
+        );
+        assert_eq!(
+            Some("text/plain; charset=utf-8"),
+            response.headers.get("Content-Type")
+        );
+
+        let mut response = get(alphabet, &[("Range", "bytes=0-1,24-")], &config);
+        assert_eq!(HttpResponseCode::PartialContent206, response.code);
+        let content_type = response.headers.get("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            "\r\n--{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+Content-Range: bytes 0-1/26\r\n\r\nab\
+\r\n--{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+Content-Range: bytes 24-25/26\r\n\r\nyz\
+\r\n--{boundary}--\r\n"
+        );
+        assert_eq!(Some(expected.into_bytes()), content(&mut response));
+        assert_eq!(None, response.headers.get("Content-Range"));
+
+        let response = get(alphabet, &[("Range", "bytes=26-")], &config);
+        assert_eq!(HttpResponseCode::RangeNotSatisfiable416, response.code);
+        assert_eq!(Some("bytes */26"), response.headers.get("Content-Range"));
+
+        // weak entity tags can't validate a range, dates can
+        let response = get(
+            alphabet,
+            &[("Range", "bytes=0-0"), ("If-Range", "W/\"x\"")],
+            &config,
+        );
+        assert_eq!(HttpResponseCode::OK200, response.code);
+        let mut response = get(
+            alphabet,
+            &[("Range", "bytes=0-0"), ("If-Range", &last_modified)],
+            &config,
+        );
+        assert_eq!(Some(b"a".to_vec()), content(&mut response));
+        let response = get(alphabet, &[("Range", "bytes=5-2")], &config);
+        assert_eq!(HttpResponseCode::OK200, response.code);
+    }
+
+    #[test]
+    fn compressible_files_are_compressed_when_accepted() {
+        fs::create_dir_all("target/qst-tests/compress").unwrap();
+        let text = "body { margin: 0; padding: 0; }\n".repeat(100);
+        fs::write("target/qst-tests/compress/style.css", &text).unwrap();
+        fs::write("target/qst-tests/compress/small.css", "a { }").unwrap();
+        fs::write("target/qst-tests/compress/image.png", &text).unwrap();
+        let config = Config::new();
+
+        let style = "/target/qst-tests/compress/style.css";
+        let identity = get(style, &[], &config);
+        assert_eq!(Some("Accept-Encoding"), identity.headers.get("Vary"));
+        assert_eq!(None, identity.headers.get("Content-Encoding"));
+        let identity_etag = identity.headers.get("ETag").unwrap().to_string();
+
+        let mut response = get(style, &[("Accept-Encoding", "gzip, deflate")], &config);
+        assert_eq!(HttpResponseCode::OK200, response.code);
+        assert_eq!(Some("gzip"), response.headers.get("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.headers.get("Vary"));
+        assert_eq!(
+            Some("text/css; charset=utf-8"),
+            response.headers.get("Content-Type")
+        );
+        let etag = response.headers.get("ETag").unwrap().to_string();
+        assert_ne!(identity_etag, etag);
+        let gzipped = compress::gzip(text.as_bytes());
+        assert_eq!(Some(gzipped.len()), response.content_length);
+        assert_eq!(Some(gzipped), content(&mut response));
+
+        let mut response = get(style, &[("Accept-Encoding", "gzip;q=0, deflate")], &config);
+        assert_eq!(Some("deflate"), response.headers.get("Content-Encoding"));
+        assert_eq!(
+            Some(compress::zlib(text.as_bytes())),
+            content(&mut response)
+        );
+
+        // each variant is revalidated with its own entity tag
+        let accept = ("Accept-Encoding", "gzip");
+        let response = get(style, &[accept, ("If-None-Match", &etag)], &config);
+        assert_eq!(HttpResponseCode::NotModified304, response.code);
+        assert_eq!(Some("Accept-Encoding"), response.headers.get("Vary"));
+        let response = get(style, &[accept, ("If-None-Match", &identity_etag)], &config);
+        assert_eq!(HttpResponseCode::OK200, response.code);
+
+        let mut response = get(style, &[accept, ("Range", "bytes=0-3")], &config);
+        assert_eq!(None, response.headers.get("Content-Encoding"));
+        assert_eq!(Some(b"body".to_vec()), content(&mut response));
+
+        for path in ["small.css", "image.png"] {
+            let response = get(
+                &format!("/target/qst-tests/compress/{path}"),
+                &[accept],
+                &config,
+            );
+            assert_eq!(None, response.headers.get("Content-Encoding"), "{path}");
+            assert_eq!(None, response.headers.get("Vary"), "{path}");
+        }
+
+        let mut config = Config::new();
+        config.compression = false;
+        let response = get(style, &[accept], &config);
+        assert_eq!(None, response.headers.get("Content-Encoding"));
+        assert_eq!(None, response.headers.get("Vary"));
+    }
+
+    #[test]
+    fn precompressed_sidecars_are_preferred() {
+        fs::create_dir_all("target/qst-tests/sidecars").unwrap();
+        fs::write("target/qst-tests/sidecars/app.js", "let a = 1;").unwrap();
+        fs::write("target/qst-tests/sidecars/app.js.br", "brotli bytes").unwrap();
+        fs::write("target/qst-tests/sidecars/app.js.gz", "gzip bytes").unwrap();
+
+        let app = "/target/qst-tests/sidecars/app.js";
+        let config = Config::new();
+        let mut response = get(
+            app,
+            &[("Accept-Encoding", "gzip, deflate, br, zstd")],
+            &config,
+        );
+        assert_eq!(Some(b"brotli bytes".to_vec()), content(&mut response));
+        assert_eq!(Some("br"), response.headers.get("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.headers.get("Vary"));
+        assert_eq!(
+            Some("text/javascript; charset=utf-8"),
+            response.headers.get("Content-Type")
+        );
+        let etag = response.headers.get("ETag").unwrap().to_string();
+        assert!(etag.ends_with("-br\""), "{etag}");
+
+        let mut response = get(app, &[("Accept-Encoding", "gzip, zstd")], &config);
+        assert_eq!(Some(b"gzip bytes".to_vec()), content(&mut response));
+        assert_eq!(Some("gzip"), response.headers.get("Content-Encoding"));
+
+        let mut response = get(app, &[("Accept-Encoding", "deflate")], &config);
+        assert_eq!(Some(b"let a = 1;".to_vec()), content(&mut response));
+        assert_eq!(None, response.headers.get("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.headers.get("Vary"));
+
+        // validators and ranges apply to the variant
+        let accept = ("Accept-Encoding", "br");
+        let response = get(app, &[accept, ("If-None-Match", &etag)], &config);
+        assert_eq!(HttpResponseCode::NotModified304, response.code);
+        let mut response = get(app, &[accept, ("Range", "bytes=0-5")], &config);
+        assert_eq!(Some(b"brotli".to_vec()), content(&mut response));
+        assert_eq!(Some("bytes 0-5/12"), response.headers.get("Content-Range"));
+        assert_eq!(Some("br"), response.headers.get("Content-Encoding"));
+
+        // turning compression off only stops the server from compressing files itself
+        let mut config = Config::new();
+        config.compression = false;
+        let mut response = get(app, &[accept], &config);
+        assert_eq!(Some(b"brotli bytes".to_vec()), content(&mut response));
+        assert_eq!(Some("br"), response.headers.get("Content-Encoding"));
+    }
+
+    #[test]
+    fn live_reload_pushes_changes_to_pages() {
+        let root = "target/qst-tests/live-reload";
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root).unwrap();
+        fs::write(format!("{root}/index.html"), "<body><p>Hi</p></body>").unwrap();
+        fs::write(format!("{root}/style.css"), "p { }").unwrap();
+        let mut config = Config::new();
+        config.mounts.set_root(root);
+        config.live_reload = true;
+        let (addr, handle, running) = run_server(config);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(client);
+        let (_, headers, body) = read_response(&mut reader);
+        let page = format!("<body><p>Hi</p>{}</body>", livereload::SCRIPT);
+        assert_eq!(page.into_bytes(), body);
+        assert_eq!(None, headers.get("Accept-Ranges"));
+
+        let mut events = TcpStream::connect(addr).unwrap();
+        events
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        events
+            .write_all(b"GET /_qst/live-reload HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut events = BufReader::new(events);
+        let mut read_until = |end: &str| {
+            let mut text = String::new();
+            while !text.ends_with(end) {
+                events.read_line(&mut text).unwrap();
+            }
+            text
+        };
+        let head = read_until("retry: 1000\n");
+        assert!(
+            head.contains("Content-Type: text/event-stream\r\n"),
+            "{head}"
+        );
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{head}");
+
+        fs::write(format!("{root}/style.css"), "p { color: red; }").unwrap();
+        assert!(read_until("\n\n").ends_with("event: css\ndata: /style.css\n\n"));
+        fs::write(format!("{root}/app.js"), "").unwrap();
+        assert!(read_until("\n\n").ends_with("event: reload\ndata: \n\n"));
+
+        // the event stream ends with the server
+        let start = Instant::now();
+        handle.shutdown();
+        let report = running.join().unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(0, report.cut_off);
+    }
+
+    #[test]
+    fn event_streams_leave_the_pool_to_other_requests() {
+        let root = "target/qst-tests/live-reload-streams";
+        fs::create_dir_all(root).unwrap();
+        fs::write(format!("{root}/index.html"), "streams").unwrap();
+        let mut config = Config::new();
+        config.mounts.set_root(root);
+        config.live_reload = true;
+        config.max_threads = Some(1);
+        let (addr, handle, running) = run_server(config);
+
+        let streams: Vec<BufReader<TcpStream>> = (0..2)
+            .map(|_| {
+                let mut events = TcpStream::connect(addr).unwrap();
+                events
+                    .set_read_timeout(Some(Duration::from_secs(10)))
+                    .unwrap();
+                events
+                    .write_all(b"GET /_qst/live-reload HTTP/1.1\r\n\r\n")
+                    .unwrap();
+                let mut events = BufReader::new(events);
+                let mut text = String::new();
+                while !text.ends_with("retry: 1000\n") {
+                    events.read_line(&mut text).unwrap();
+                }
+                assert!(text.contains("Connection: close\r\n"), "{text}");
+                events
+            })
+            .collect();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let (status, _, body) = read_response(&mut BufReader::new(client));
+        assert_eq!("HTTP/1.1 200 Ok", status);
+        assert!(body.starts_with(b"streams"));
+
+        let start = Instant::now();
+        handle.shutdown();
+        let report = running.join().unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(0, report.cut_off);
+        drop(streams);
+    }
+
+    #[test]
+    fn spa_mounts_fall_back_to_their_default_file() {
+        let root = "target/qst-tests/spa";
+        fs::create_dir_all(format!("{root}/app/assets")).unwrap();
+        fs::create_dir_all(format!("{root}/docs")).unwrap();
+        fs::write(format!("{root}/app/index.html"), "app").unwrap();
+        fs::write(format!("{root}/app/assets/main.js"), "main").unwrap();
+        fs::write(format!("{root}/docs/index.html"), "docs").unwrap();
+        let mut config = Config::new();
+        config.mounts.set_root(&format!("{root}/app"));
+        config.mounts.insert("/docs", &format!("{root}/docs"));
+        config.spa_prefixes.push(String::new());
+
+        for path in ["/settings/profile", "/settings/", "/users/42?tab=posts"] {
+            let mut response = get(path, &[], &config);
+            assert_eq!(HttpResponseCode::OK200, response.code, "{path}");
+            assert_eq!(Some(b"app".to_vec()), content(&mut response), "{path}");
+            assert_eq!(
+                Some("text/html; charset=utf-8"),
+                response.headers.get("Content-Type")
+            );
+        }
+        let mut response = get("/assets/main.js", &[], &config);
+        assert_eq!(Some(b"main".to_vec()), content(&mut response));
+
+        // assets are missing for real, and the fallback is scoped to its mount
+        for path in ["/assets/missing.js", "/logo.png", "/docs/settings/profile"] {
+            assert_eq!(
+                HttpResponseCode::NotFound404,
+                get(path, &[], &config).code,
+                "{path}"
+            );
+        }
+        let mut response = get("/docs/", &[], &config);
+        assert_eq!(Some(b"docs".to_vec()), content(&mut response));
+    }
+
+    #[test]
+    fn proxied_requests_reach_the_upstream_and_come_back() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let upstream = thread::spawn(move || {
+            let (stream, _) = upstream.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut head = String::new();
+            while !head.ends_with("\r\n\r\n") {
+                reader.read_line(&mut head).unwrap();
+            }
+            let mut body = vec![0; 12];
+            reader.read_exact(&mut body).unwrap();
+            (&stream)
+                .write_all(
+                    format!(
+                        "HTTP/1.1 201 Created\r\nLocation: http://{upstream_addr}/v1/users/7\r\n\
+Connection: keep-alive\r\nX-Custom: yes\r\nContent-Length: 5\r\n\r\nhello"
+                    )
+                    .as_bytes(),
+                )
+                .unwrap();
+            (head, body)
+        });
+
+        let mut config = Config::new();
+        let url = format!("http://{upstream_addr}/v1");
+        config
+            .proxies
+            .insert("/api", proxy::Upstream::parse(&url).unwrap());
+        let (mut client, responder) = connect_to_responder(config);
+        client
+            .write_all(
+                b"POST /api/users?x=1 HTTP/1.1\r\nHost: localhost:6969\r\nX-Forwarded-For: 10.0.0.1\r\n\
+Connection: keep-alive, X-Secret\r\nX-Secret: hop\r\nAccept: */*\r\n\
+Transfer-Encoding: chunked\r\n\r\n7\r\npayload\r\n5\r\n, too\r\n0\r\n\r\n\
+GET /api/users HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut reader = BufReader::new(client);
+
+        let (status, headers, body) = read_response(&mut reader);
+        assert_eq!("HTTP/1.1 201 Created", status);
+        assert_eq!(b"hello".to_vec(), body);
+        assert_eq!(Some("yes"), headers.get("X-Custom"));
+        assert_eq!(
+            Some("http://localhost:6969/api/users/7"),
+            headers.get("Location")
+        );
+        assert_eq!(Some("keep-alive"), headers.get("Connection"));
+
+        let (head, body) = upstream.join().unwrap();
+        assert_eq!(b"payload, too".to_vec(), body);
+        let mut lines = head.lines();
+        assert_eq!(Some("POST /v1/users?x=1 HTTP/1.0"), lines.next());
+        let forwarded: Vec<&str> = lines.filter(|line| !line.is_empty()).collect();
+        let expected_host = format!("Host: {upstream_addr}");
+        for line in [
+            "Accept: */*",
+            &expected_host,
+            "X-Forwarded-For: 10.0.0.1, 127.0.0.1",
+            "X-Forwarded-Host: localhost:6969",
+            "X-Forwarded-Proto: http",
+            "Content-Length: 12",
+            "Connection: close",
+        ] {
+            assert!(forwarded.contains(&line), "{line} in {forwarded:?}");
+        }
+        assert!(!forwarded.iter().any(|line| line.starts_with("X-Secret")));
+        assert_eq!(7, forwarded.len(), "{forwarded:?}");
+
+        // the upstream is gone now
+        let (status, headers, _) = read_response(&mut reader);
+        assert_eq!("HTTP/1.1 502 Bad Gateway", status);
+        assert_eq!(Some("close"), headers.get("Connection"));
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn proxied_paths_are_matched_once_decoded_and_checked() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let upstream = thread::spawn(move || {
+            let (stream, _) = upstream.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut head = String::new();
+            while !head.ends_with("\r\n\r\n") {
+                reader.read_line(&mut head).unwrap();
+            }
+            (&stream)
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+5;x=y\r\nhello\r\n0\r\nX-Trailer: dropped\r\n\r\n",
+                )
+                .unwrap();
+            head
+        });
+        let mut config = Config::new();
+        let url = format!("http://{upstream_addr}");
+        config
+            .proxies
+            .insert("/api", proxy::Upstream::parse(&url).unwrap());
+        let (mut client, responder) = connect_to_responder(config);
+        client
+            .write_all(
+                b"GET /api/../Cargo.toml HTTP/1.1\r\n\r\n\
+                GET /%61pi/a%20b?x=%31 HTTP/1.0\r\n\r\n",
+            )
+            .unwrap();
+        let mut reader = BufReader::new(client);
+
+        let (status, _, _) = read_response(&mut reader);
+        assert_eq!("HTTP/1.1 403 Forbidden", status);
+
+        // the chunks of the upstream are decoded
+        let (status, headers) = read_head(&mut reader);
+        assert_eq!("HTTP/1.1 200 Ok", status);
+        assert_eq!(None, headers.get("Transfer-Encoding"));
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!(b"hello".to_vec(), content);
+
+        let head = upstream.join().unwrap();
+        assert!(
+            head.starts_with("GET /api/a%20b?x=%31 HTTP/1.0\r\n"),
+            "{head}"
+        );
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn unknown_lengths_are_chunked_for_http11_clients() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let upstream = thread::spawn(move || {
+            for _ in 0..2 {
+                let (stream, _) = upstream.accept().unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") {
+                    reader.read_line(&mut head).unwrap();
+                }
+                // delimited by closing the connection
+                (&stream)
+                    .write_all(b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\nstreamed")
+                    .unwrap();
+            }
+        });
+        let config = || {
+            let mut config = Config::new();
+            let url = format!("http://{upstream_addr}");
+            config
+                .proxies
+                .insert("/api", proxy::Upstream::parse(&url).unwrap());
+            config
+        };
+
+        let (mut client, responder) = connect_to_responder(config());
+        client
+            .write_all(
+                b"GET /api/a HTTP/1.1\r\n\r\n\
+                GET /index.html HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut reader = BufReader::new(client);
+        let (status, headers) = read_head(&mut reader);
+        assert_eq!("HTTP/1.1 200 Ok", status);
+        assert_eq!(Some("chunked"), headers.get("Transfer-Encoding"));
+        assert_eq!(None, headers.get("Content-Length"));
+        assert_eq!(Some("keep-alive"), headers.get("Connection"));
+        let mut content = Vec::new();
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size).unwrap();
+            let size = usize::from_str_radix(size.trim_end(), 16).unwrap();
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).unwrap();
+            assert!(chunk.ends_with(b"\r\n"));
+            if size == 0 {
+                break;
+            }
+            content.extend_from_slice(&chunk[..size]);
+        }
+        assert_eq!(b"streamed".to_vec(), content);
+        // the connection goes on after the last chunk
+        let (status, _, body) = read_response(&mut reader);
+        assert_eq!("HTTP/1.1 200 Ok", status);
+        assert_eq!(fs::read("index.html").unwrap(), body);
+        responder.join().unwrap();
+
+        // HTTP/1.0 clients don't know chunks
+        let (mut client, responder) = connect_to_responder(config());
+        client.write_all(b"GET /api/a HTTP/1.0\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(client);
+        let (_, headers) = read_head(&mut reader);
+        assert_eq!(None, headers.get("Transfer-Encoding"));
+        assert_eq!(Some("close"), headers.get("Connection"));
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!(b"streamed".to_vec(), content);
+        responder.join().unwrap();
+        upstream.join().unwrap();
+    }
 }
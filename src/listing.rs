@@ -0,0 +1,303 @@
+use crate::date::UtcDateTime;
+use crate::url::percent_encode_segment;
+use std::fs;
+use std::io::Result as IoResult;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An entry of a directory listing.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Whether an entry is left out of the listings. Names starting with `_` are private at any
+/// depth here, while the server only refuses to send them at the top level.
+pub fn is_hidden(name: &str) -> bool {
+    name.starts_with('_')
+}
+
+/// Reads the entries of a directory, without the hidden ones, directories first and then by name.
+pub fn read_entries(dir: &Path) -> IoResult<Vec<DirEntry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            // can't be linked to reliably
+            Err(_) => continue,
+        };
+        if is_hidden(&name) {
+            continue;
+        }
+        // follows symlinks, so linked directories are listed as directories
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        entries.push(DirEntry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encodes a name so it can be used as a relative link.
+fn encode_link(name: &str) -> String {
+    // a name like "a:b" would be taken as a scheme
+    format!("./{}", percent_encode_segment(name))
+}
+
+fn unix_secs(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(_) => 0,
+    }
+}
+
+fn human_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if size < 1024 {
+        return format!("{size} B");
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+const SORT_SCRIPT: &str = "\
+document.querySelectorAll('th[data-col]').forEach(function (th) {
+    th.addEventListener('click', function () {
+        var col = +th.dataset.col, asc = th.dataset.asc !== 'true';
+        th.dataset.asc = asc;
+        var body = document.querySelector('tbody');
+        var rows = Array.prototype.slice.call(body.querySelectorAll('tr[data-name]'));
+        rows.sort(function (a, b) {
+            var x = a.children[col].dataset.sort, y = b.children[col].dataset.sort;
+            var cmp = col === 0 ? x.localeCompare(y) : x - y;
+            return (a.dataset.dir - b.dataset.dir) * -1 || (asc ? cmp : -cmp);
+        });
+        rows.forEach(function (row) { body.appendChild(row); });
+    });
+});
+";
+
+/// Renders the listing of the directory at `url_path` as an HTML page. Columns are sorted by
+/// clicking their headers.
+pub fn render_html(url_path: &str, entries: &[DirEntry]) -> String {
+    let title = escape_html(&format!("Index of {url_path}"));
+    let mut rows = String::new();
+    if url_path != "/" {
+        rows.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let (size, size_key) = if entry.is_dir {
+            (String::from("-"), String::from("-1"))
+        } else {
+            (human_size(entry.size), entry.size.to_string())
+        };
+        let (modified, modified_key) = match entry.modified {
+            Some(time) => (
+                UtcDateTime::from_system_time(time)
+                    .to_rfc3339()
+                    .replace('T', " ")
+                    .replace('Z', ""),
+                unix_secs(time).to_string(),
+            ),
+            None => (String::new(), String::from("0")),
+        };
+        rows.push_str(&format!(
+            "<tr data-name data-dir=\"{}\">\
+<td data-sort=\"{name}\"><a href=\"{href}{suffix}\">{name}{suffix}</a></td>\
+<td data-sort=\"{size_key}\">{size}</td>\
+<td data-sort=\"{modified_key}\">{modified}</td></tr>\n",
+            u8::from(entry.is_dir),
+            name = escape_html(&entry.name),
+            href = encode_link(&entry.name),
+        ));
+    }
+
+    format!(
+        "\
+<!DOCTYPE html>
+<html lang=\"en\">
+    <head>
+        <meta charset=\"utf-8\">
+        <title>{title}</title>
+        <style>
+            body {{ font-family: sans-serif; }}
+            table {{ border-collapse: collapse; }}
+            th {{ cursor: pointer; text-align: left; }}
+            th, td {{ padding: 0.2em 1.5em 0.2em 0; }}
+        </style>
+    </head>
+
+    <body>
+        <h1>{title}</h1>
+        <table>
+            <thead>
+                <tr><th data-col=\"0\">Name</th><th data-col=\"1\">Size</th><th data-col=\"2\">Last modified (UTC)</th></tr>
+            </thead>
+            <tbody>
+{rows}            </tbody>
+        </table>
+        <script>
+{SORT_SCRIPT}        </script>
+    </body>
+</html>
+"
+    )
+}
+
+/// Renders the listing of the directory at `url_path` as JSON.
+///
+/// # Examples
+/// ```
+/// use qst::listing::{render_json, DirEntry};
+/// let entries = vec![DirEntry {
+///     name: String::from("app.js"),
+///     is_dir: false,
+///     size: 42,
+///     modified: None,
+/// }];
+/// assert_eq!(
+///     r#"{"path":"/dist/","entries":[{"name":"app.js","type":"file","size":42,"modified":null}]}"#,
+///     render_json("/dist/", &entries)
+/// );
+/// ```
+pub fn render_json(url_path: &str, entries: &[DirEntry]) -> String {
+    let entries: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let kind = if entry.is_dir { "directory" } else { "file" };
+            let size = if entry.is_dir {
+                String::from("null")
+            } else {
+                entry.size.to_string()
+            };
+            let modified = match entry.modified {
+                Some(time) => format!("\"{}\"", UtcDateTime::from_system_time(time).to_rfc3339()),
+                None => String::from("null"),
+            };
+            format!(
+                "{{\"name\":\"{}\",\"type\":\"{kind}\",\"size\":{size},\"modified\":{modified}}}",
+                escape_json(&entry.name)
+            )
+        })
+        .collect();
+    format!(
+        "{{\"path\":\"{}\",\"entries\":[{}]}}",
+        escape_json(url_path),
+        entries.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn entries() -> Vec<DirEntry> {
+        vec![
+            DirEntry {
+                name: String::from("assets"),
+                is_dir: true,
+                size: 0,
+                modified: None,
+            },
+            DirEntry {
+                name: String::from("<b>&\"quotes\".txt"),
+                is_dir: false,
+                size: 2048,
+                modified: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            },
+        ]
+    }
+
+    #[test]
+    fn listing_reads_entries_without_hidden_ones() {
+        let dir = Path::new("target/qst-tests/listing");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("b.js"), "b").unwrap();
+        fs::write(dir.join("a.css"), "aa").unwrap();
+        fs::write(dir.join("_private.txt"), "secret").unwrap();
+
+        let entries = read_entries(dir).unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| &entry.name[..]).collect();
+        assert_eq!(vec!["sub", "a.css", "b.js"], names);
+        assert!(entries[0].is_dir);
+        assert_eq!(2, entries[1].size);
+        assert!(entries[1].modified.is_some());
+    }
+
+    #[test]
+    fn html_listing_escapes_names_and_links_parent() {
+        let html = render_html("/docs/", &entries());
+        assert!(html.contains("<title>Index of /docs/</title>"));
+        assert!(html.contains("<a href=\"../\">../</a>"));
+        assert!(html.contains("<a href=\"./assets/\">assets/</a>"));
+        assert!(html.contains(
+            "<a href=\"./%3Cb%3E%26%22quotes%22.txt\">&lt;b&gt;&amp;&quot;quotes&quot;.txt</a>"
+        ));
+        assert!(html.contains("<td data-sort=\"2048\">2.0 KiB</td>"));
+        assert!(html.contains("<td data-sort=\"1700000000\">2023-11-14 22:13:20</td>"));
+
+        let html = render_html("/", &entries());
+        assert!(!html.contains("../"));
+    }
+
+    #[test]
+    fn json_listing_escapes_names() {
+        assert_eq!(
+            "{\"path\":\"/\",\"entries\":[\
+{\"name\":\"assets\",\"type\":\"directory\",\"size\":null,\"modified\":null},\
+{\"name\":\"<b>&\\\"quotes\\\".txt\",\"type\":\"file\",\"size\":2048,\
+\"modified\":\"2023-11-14T22:13:20Z\"}]}",
+            render_json("/", &entries())
+        );
+    }
+}
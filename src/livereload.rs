@@ -0,0 +1,350 @@
+use crate::body::Body;
+use crate::http::{Headers, HttpResponse, HttpResponseCode};
+use crate::mount::MountTable;
+use crate::url;
+use crate::watch::{Event, WatchHandle, Watcher};
+use std::fs;
+use std::io::Result as IoResult;
+use std::iter;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Path of the Server-Sent Events stream browsers listen to. Paths starting with `_` are never
+/// served from the disk, so it can't hide a file.
+pub const EVENTS_PATH: &str = "/_qst/live-reload";
+
+/// How long an event stream may stay silent before a comment is sent, so proxies and browsers
+/// don't take it for dead.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often [`LiveReload::wait_streams`] checks whether the event streams ended.
+const STREAM_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Script injected into HTML pages. Reloads the page on any change, or only the stylesheets when
+/// nothing but CSS changed: the ones whose path changed, or all of them if none did, as the
+/// changed file may be imported by another one.
+pub const SCRIPT: &str = r#"<script>
+(() => {
+  const source = new EventSource("/_qst/live-reload");
+  source.addEventListener("reload", () => location.reload());
+  source.addEventListener("css", (event) => {
+    const changed = event.data.split("\n");
+    const links = [...document.querySelectorAll('link[rel="stylesheet"]')];
+    const matching = links.filter((link) => changed.includes(new URL(link.href).pathname));
+    for (const link of matching.length ? matching : links) {
+      const url = new URL(link.href);
+      url.searchParams.set("qst-reload", Date.now());
+      link.href = url.href;
+    }
+  });
+})();
+</script>
+"#;
+
+/// What browsers are told to do after files changed.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Change {
+    /// Reload the page.
+    Reload,
+    /// Only stylesheets changed, at these URL paths.
+    Css(Vec<String>),
+}
+
+impl Change {
+    /// The change as a Server-Sent Event.
+    fn to_event(&self) -> String {
+        match self {
+            Change::Reload => String::from("event: reload\ndata: \n\n"),
+            Change::Css(paths) => {
+                let data: String = paths.iter().map(|path| format!("data: {path}\n")).collect();
+                format!("event: css\n{data}\n")
+            }
+        }
+    }
+}
+
+/// Inserts [`SCRIPT`] into an HTML page, before its last `</body>`, or at the end if there is
+/// none.
+///
+/// # Examples
+/// ```
+/// use qst::livereload::{inject_script, SCRIPT};
+/// let page = inject_script(b"<body>Hi</BODY></html>");
+/// assert_eq!(format!("<body>Hi{SCRIPT}</BODY></html>").into_bytes(), page);
+/// ```
+pub fn inject_script(html: &[u8]) -> Vec<u8> {
+    let position = html
+        .windows(7)
+        .rposition(|window| window.eq_ignore_ascii_case(b"</body>"))
+        .unwrap_or(html.len());
+    let mut page = Vec::with_capacity(html.len() + SCRIPT.len());
+    page.extend_from_slice(&html[..position]);
+    page.extend_from_slice(SCRIPT.as_bytes());
+    page.extend_from_slice(&html[position..]);
+    page
+}
+
+/// Turns the events of the directory mounted at `prefix` into the change to announce, if any.
+fn change_of(prefix: &str, dir: &Path, events: &[Event]) -> Option<Change> {
+    let mut css = Vec::new();
+    for path in events.iter().flat_map(Event::paths) {
+        if path
+            .extension()
+            .map_or(true, |extension| extension != "css")
+        {
+            return Some(Change::Reload);
+        }
+        let relative = path.strip_prefix(dir).unwrap_or(path);
+        let segments: Vec<String> = relative
+            .iter()
+            .map(|segment| url::percent_encode_segment(&segment.to_string_lossy()))
+            .collect();
+        css.push(format!("{prefix}/{}", segments.join("/")));
+    }
+    if css.is_empty() {
+        None
+    } else {
+        Some(Change::Css(css))
+    }
+}
+
+/// The mounts of `mounts` to watch: a directory already watched through another mount, which
+/// contains it, would announce every change twice.
+fn watched_mounts(mounts: &MountTable) -> Vec<(&str, &str)> {
+    // directories are compared once resolved, `dist` and `./dist/` are the same
+    let dirs: Vec<PathBuf> = mounts
+        .iter()
+        .map(|(_, dir)| fs::canonicalize(dir).unwrap_or_else(|_| PathBuf::from(dir)))
+        .collect();
+    mounts
+        .iter()
+        .zip(&dirs)
+        .enumerate()
+        .filter(|(i, (_, dir))| {
+            // of the same directory mounted twice, the shortest prefix is watched, they come last
+            !dirs.iter().enumerate().any(|(j, other)| {
+                j != *i && Watcher::new(other).covers(dir) && (other != *dir || j > *i)
+            })
+        })
+        .map(|(_, (mount, _))| mount)
+        .collect()
+}
+
+/// Watches the served directories and pushes their changes to the browsers listening on
+/// [`EVENTS_PATH`].
+#[derive(Debug, Default)]
+pub struct LiveReload {
+    clients: Mutex<Vec<Sender<Change>>>,
+    closed: AtomicBool,
+    watchers: Mutex<Vec<WatchHandle>>,
+    streams: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl LiveReload {
+    /// Creates a hub without any watcher. Changes are only sent through [`LiveReload::notify`].
+    pub fn new() -> LiveReload {
+        LiveReload::default()
+    }
+
+    /// Starts watching every directory of `mounts` with a [`Watcher`] each.
+    pub fn watch(mounts: &MountTable) -> Arc<LiveReload> {
+        let live_reload = Arc::new(LiveReload::new());
+        let watchers = watched_mounts(mounts)
+            .into_iter()
+            .map(|(prefix, dir)| {
+                let prefix = prefix.to_string();
+                let dir = PathBuf::from(dir);
+                // the hub owns the watchers, they must not keep it alive
+                let hub = Arc::downgrade(&live_reload);
+                Watcher::new(&dir).spawn(move |events| {
+                    let change = change_of(&prefix, &dir, &events);
+                    if let (Some(hub), Some(change)) = (hub.upgrade(), change) {
+                        hub.notify(change);
+                    }
+                })
+            })
+            .collect();
+        *live_reload
+            .watchers
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = watchers;
+        live_reload
+    }
+
+    /// Sends `change` to every browser listening.
+    pub fn notify(&self, change: Change) {
+        let mut clients = self.clients.lock().unwrap_or_else(|err| err.into_inner());
+        // browsers that left are forgotten
+        clients.retain(|client| client.send(change.clone()).is_ok());
+    }
+
+    /// Stops the watchers and ends every event stream, so the server can shut down.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.clients
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clear();
+        let watchers = mem::take(&mut *self.watchers.lock().unwrap_or_else(|err| err.into_inner()));
+        for watcher in watchers {
+            watcher.stop();
+        }
+    }
+
+    /// Runs `stream`, which writes an event stream, on a thread of its own. Event streams last as
+    /// long as the page is open, on a worker of the pool they would starve the other requests.
+    pub fn spawn_stream<F>(&self, stream: F) -> IoResult<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let handle = thread::Builder::new()
+            .name(String::from("qst-events"))
+            .spawn(stream)?;
+        let mut streams = self.streams.lock().unwrap_or_else(|err| err.into_inner());
+        streams.retain(|stream| !stream.is_finished());
+        streams.push(handle);
+        Ok(())
+    }
+
+    /// Waits until every event stream ended, for at most `timeout`. Returns whether they all
+    /// ended in time.
+    pub fn wait_streams(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut streams = self.streams.lock().unwrap_or_else(|err| err.into_inner());
+            streams.retain(|stream| !stream.is_finished());
+            if streams.is_empty() {
+                return true;
+            }
+            drop(streams);
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(STREAM_POLL_INTERVAL);
+        }
+    }
+
+    /// Builds the response to a request of [`EVENTS_PATH`]: an event stream that lasts until the
+    /// browser leaves or the hub is closed.
+    pub fn events(&self) -> HttpResponse {
+        let (sender, receiver) = mpsc::channel();
+        if !self.closed.load(Ordering::SeqCst) {
+            self.clients
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .push(sender);
+        }
+        let mut headers = Headers::new();
+        headers.insert("Content-Type", "text/event-stream");
+        headers.insert("Cache-Control", "no-cache");
+        HttpResponse {
+            code: HttpResponseCode::OK200,
+            content: Some(event_stream(receiver)),
+            content_length: None,
+            headers,
+        }
+    }
+}
+
+/// Streams the changes received as Server-Sent Events, starting with a retry delay for when the
+/// server restarts.
+fn event_stream(receiver: Receiver<Change>) -> Body {
+    let first = iter::once(Ok(b"retry: 1000\n\n".to_vec()));
+    let events = iter::from_fn(move || match receiver.recv_timeout(HEARTBEAT_INTERVAL) {
+        Ok(change) => Some(Ok(change.to_event().into_bytes())),
+        Err(RecvTimeoutError::Timeout) => Some(Ok(b": heartbeat\n\n".to_vec())),
+        Err(RecvTimeoutError::Disconnected) => None,
+    });
+    Body::Chunks(Box::new(first.chain(events)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_goes_before_the_end_of_the_body() {
+        let page = inject_script(b"<body><p>a</body></p></body>\n");
+        assert_eq!(
+            format!("<body><p>a</body></p>{SCRIPT}</body>\n").into_bytes(),
+            page
+        );
+        assert_eq!(format!("<p>a{SCRIPT}").into_bytes(), inject_script(b"<p>a"));
+    }
+
+    #[test]
+    fn changes_reload_unless_only_css_changed() {
+        let dir = Path::new("site");
+        let css = Event::Modified(dir.join("css/a b.css"));
+        assert_eq!(None, change_of("", dir, &[]));
+        assert_eq!(
+            Some(Change::Css(vec![String::from("/assets/css/a%20b.css")])),
+            change_of("/assets", dir, std::slice::from_ref(&css))
+        );
+        let renamed = Event::Renamed {
+            from: dir.join("a.css"),
+            to: dir.join("b.css"),
+        };
+        assert_eq!(
+            Some(Change::Css(vec![
+                String::from("/css/a%20b.css"),
+                String::from("/a.css"),
+                String::from("/b.css"),
+            ])),
+            change_of("", dir, &[css.clone(), renamed])
+        );
+        assert_eq!(
+            Some(Change::Reload),
+            change_of("", dir, &[css, Event::Removed(dir.join("index.html"))])
+        );
+        assert_eq!(
+            Some(Change::Reload),
+            change_of("", dir, &[Event::Created(dir.join("app.js"))])
+        );
+    }
+
+    #[test]
+    fn nested_mounts_are_watched_once() {
+        let root = "target/qst-tests/live-reload-nested";
+        for dir in ["assets", "node_modules/lib"] {
+            fs::create_dir_all(format!("{root}/{dir}")).unwrap();
+        }
+        let mut mounts = MountTable::new();
+        mounts.set_root(root);
+        mounts.insert("/static", &format!("{root}/assets"));
+        mounts.insert("/lib", &format!("{root}/node_modules/lib"));
+        mounts.insert("/again", &format!("./{root}/"));
+        let prefixes: Vec<&str> = watched_mounts(&mounts)
+            .into_iter()
+            .map(|(prefix, _)| prefix)
+            .collect();
+        // the root doesn't watch its node_modules
+        assert_eq!(vec!["/lib", ""], prefixes);
+    }
+
+    #[test]
+    fn events_are_streamed_until_the_hub_closes() {
+        let live_reload = LiveReload::new();
+        let events = live_reload.events();
+        live_reload.notify(Change::Reload);
+        live_reload.notify(Change::Css(vec![
+            String::from("/a.css"),
+            String::from("/b.css"),
+        ]));
+        live_reload.close();
+        assert_eq!(
+            Some("text/event-stream"),
+            events.headers.get("Content-Type")
+        );
+        let stream = events.content.unwrap().into_bytes().unwrap();
+        assert_eq!(
+            "retry: 1000\n\nevent: reload\ndata: \n\nevent: css\ndata: /a.css\ndata: /b.css\n\n",
+            String::from_utf8(stream).unwrap()
+        );
+    }
+}
@@ -8,9 +8,17 @@ fn main() {
         process::exit(1);
     });
     match qst::start_server(config) {
-        Ok(_) => println!("Bye Bye!"),
-        Err(msg) => {
-            eprintln!("Server failed with message: {msg}");
+        Ok(report) => {
+            if report.connection_errors > 0 {
+                eprintln!("{} connections failed on the client side.", report.connection_errors);
+            }
+            if report.cut_off > 0 {
+                eprintln!("{} connections were cut off while shutting down.", report.cut_off);
+            }
+            println!("Bye Bye!");
+        },
+        Err(err) => {
+            eprintln!("Server failed with message: {err}");
             process::exit(1);
         },
     }
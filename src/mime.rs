@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Type sent for files whose extension is not in the table.
+pub const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+
+const DEFAULT_MIME_TYPES: &[(&str, &str)] = &[
+    // documents
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("xhtml", "application/xhtml+xml"),
+    ("css", "text/css"),
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("xml", "application/xml"),
+    ("pdf", "application/pdf"),
+    // scripts and data
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("cjs", "text/javascript"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("wasm", "application/wasm"),
+    // images
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    // fonts
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("eot", "application/vnd.ms-fontobject"),
+    // audio and video
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("ogg", "audio/ogg"),
+    ("flac", "audio/flac"),
+    ("m4a", "audio/mp4"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    // archives
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+];
+
+/// Maps file extensions to MIME types. Comes filled with the common types of the web and can be
+/// extended or overridden with [`MimeTable::insert`].
+#[derive(Debug, PartialEq, Clone)]
+pub struct MimeTable {
+    types: HashMap<String, String>,
+}
+
+impl Default for MimeTable {
+    fn default() -> MimeTable {
+        MimeTable::new()
+    }
+}
+
+impl MimeTable {
+    /// Creates a new table with the default mappings.
+    pub fn new() -> MimeTable {
+        let types = DEFAULT_MIME_TYPES
+            .iter()
+            .map(|(ext, mime)| (ext.to_string(), mime.to_string()))
+            .collect();
+        MimeTable { types }
+    }
+
+    /// Adds a mapping, replacing any previous mapping of the same extension. The extension is
+    /// case-insensitive and may be given with or without the leading dot.
+    pub fn insert(&mut self, extension: &str, mime: &str) {
+        let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+        self.types.insert(extension, mime.to_string());
+    }
+
+    /// Returns the MIME type mapped to the extension of `path`, if any.
+    pub fn lookup(&self, path: &str) -> Option<&str> {
+        let extension = Path::new(path).extension()?.to_str()?;
+        self.types
+            .get(&extension.to_ascii_lowercase())
+            .map(|mime| &mime[..])
+    }
+
+    /// Returns the value of the `Content-Type` header to send with `path`. Text types get an
+    /// explicit UTF-8 charset, unknown extensions get [`DEFAULT_MIME_TYPE`].
+    ///
+    /// # Examples
+    /// ```
+    /// use qst::mime::MimeTable;
+    /// let table = MimeTable::new();
+    /// assert_eq!("text/css; charset=utf-8", table.content_type("./style.css"));
+    /// assert_eq!("image/png", table.content_type("./logo.PNG"));
+    /// ```
+    pub fn content_type(&self, path: &str) -> String {
+        match self.lookup(path) {
+            Some(mime) if is_text(mime) && !mime.contains("charset") => {
+                format!("{mime}; charset=utf-8")
+            }
+            Some(mime) => mime.to_string(),
+            None => DEFAULT_MIME_TYPE.to_string(),
+        }
+    }
+}
+
+/// Whether a MIME type is textual and thus should carry a charset.
+pub fn is_text(mime: &str) -> bool {
+    let essence = mime.split(';').next().unwrap_or("").trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+xml")
+        || essence.ends_with("+json")
+        || matches!(
+            essence,
+            "application/json" | "application/xml" | "application/javascript"
+        )
+}
+
+/// Whether a MIME type is worth compressing. Images, fonts, media and archives other than SVG
+/// and WebAssembly are already compressed.
+pub fn is_compressible(mime: &str) -> bool {
+    let essence = mime.split(';').next().unwrap_or("").trim();
+    is_text(mime)
+        || matches!(
+            essence,
+            "application/wasm" | "application/manifest+json" | "image/x-icon" | "image/bmp"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_finds_common_types() {
+        let table = MimeTable::new();
+        assert_eq!(
+            "text/html; charset=utf-8",
+            table.content_type("./index.html")
+        );
+        assert_eq!(
+            "text/javascript; charset=utf-8",
+            table.content_type("./app.mjs")
+        );
+        assert_eq!("application/wasm", table.content_type("./pkg/app_bg.wasm"));
+        assert_eq!("font/woff2", table.content_type("./fonts/inter.woff2"));
+        assert_eq!(
+            "image/svg+xml; charset=utf-8",
+            table.content_type("./icon.svg")
+        );
+    }
+
+    #[test]
+    fn table_falls_back_to_octet_stream() {
+        let table = MimeTable::new();
+        assert_eq!(DEFAULT_MIME_TYPE, table.content_type("./data.unknown"));
+        assert_eq!(DEFAULT_MIME_TYPE, table.content_type("./Makefile"));
+        assert_eq!(DEFAULT_MIME_TYPE, table.content_type("./.hidden"));
+    }
+
+    #[test]
+    fn table_accepts_overrides() {
+        let mut table = MimeTable::new();
+        table.insert(".JS", "application/javascript");
+        table.insert("glb", "model/gltf-binary");
+        table.insert("txt", "text/plain; charset=latin1");
+        assert_eq!(
+            "application/javascript; charset=utf-8",
+            table.content_type("./app.js")
+        );
+        assert_eq!("model/gltf-binary", table.content_type("./scene.glb"));
+        assert_eq!("text/plain; charset=latin1", table.content_type("./a.txt"));
+    }
+}
@@ -0,0 +1,274 @@
+use std::fs;
+use std::io::Result as IoResult;
+use std::path::PathBuf;
+
+/// Directory served at `/` when no root is given.
+pub const DEFAULT_ROOT: &str = ".";
+
+/// What to do with symlinks found in the served directories.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SymlinkPolicy {
+    /// Follow every symlink, wherever it points.
+    Follow,
+    /// Follow symlinks as long as the target stays within the directory of the mount.
+    WithinRoot,
+    /// Refuse any path going through a symlink below the directory of the mount.
+    Deny,
+}
+
+/// A URL path mapped to the filesystem by a [`MountTable`].
+#[derive(Debug, PartialEq, Clone)]
+pub struct Resolved {
+    /// Prefix of the mount serving the path, as given by [`normalize_prefix`].
+    pub prefix: String,
+    /// Directory of the mount serving the path.
+    pub dir: String,
+    /// Path of the target, within `dir`. A trailing `/` is kept.
+    pub path: String,
+}
+
+impl Resolved {
+    /// Checks that `path`, either [`Resolved::path`] or a file below it, may be served under
+    /// `policy`. Both `path` and the directory of the mount are canonicalized, so `..` or symlinks
+    /// can't lead out of the mount. Returns `Ok(false)` if the path escapes or goes through a
+    /// denied symlink, and `Err` if it doesn't exist.
+    pub fn allows(&self, path: &str, policy: SymlinkPolicy) -> IoResult<bool> {
+        let target = fs::canonicalize(path)?;
+        let root = fs::canonicalize(&self.dir)?;
+        match policy {
+            SymlinkPolicy::Follow => Ok(true),
+            SymlinkPolicy::WithinRoot => Ok(target.starts_with(root)),
+            SymlinkPolicy::Deny => {
+                // the directory of the mount itself may be a link, it was configured as is
+                let rest = match path.strip_prefix(&self.dir[..]) {
+                    Some(rest) => rest,
+                    None => return Ok(false),
+                };
+                let mut current = PathBuf::from(&self.dir);
+                for segment in rest.split('/').filter(|segment| !segment.is_empty()) {
+                    current.push(segment);
+                    if fs::symlink_metadata(&current)?.file_type().is_symlink() {
+                        return Ok(false);
+                    }
+                }
+                Ok(target.starts_with(root))
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct Mount {
+    /// URL prefix without the trailing `/`, empty for the root mount.
+    prefix: String,
+    /// Directory without the trailing `/`.
+    dir: String,
+}
+
+/// Writes a URL prefix the way mounts are keyed: with a leading `/` and without the trailing one,
+/// so the root is the empty prefix.
+///
+/// # Examples
+/// ```
+/// use qst::mount::normalize_prefix;
+/// assert_eq!("/app", normalize_prefix("app/"));
+/// assert_eq!("", normalize_prefix("/"));
+/// ```
+pub fn normalize_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("/{prefix}")
+    }
+}
+
+fn trim_dir(dir: &str) -> String {
+    match dir.trim_end_matches('/') {
+        // the filesystem root itself
+        "" if dir.starts_with('/') => String::from("/"),
+        "" => String::from(DEFAULT_ROOT),
+        dir => dir.to_string(),
+    }
+}
+
+/// Maps URL prefixes to the directories served under them. Comes with [`DEFAULT_ROOT`] mounted at
+/// `/`. Prefixes match whole path segments and the longest one wins, so `/assets` serves
+/// `/assets/logo.png` but not `/assets-old/logo.png`.
+///
+/// Resolving never leaves the directory of the mount: paths are checked for `..` and `//` before,
+/// by [`crate::http::HttpRequest::match_fetch`], and only what follows the prefix is appended to
+/// the directory.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MountTable {
+    mounts: Vec<Mount>,
+}
+
+impl Default for MountTable {
+    fn default() -> MountTable {
+        MountTable::new()
+    }
+}
+
+impl MountTable {
+    /// Creates a new table with only [`DEFAULT_ROOT`] mounted at `/`.
+    pub fn new() -> MountTable {
+        let mut table = MountTable { mounts: Vec::new() };
+        table.set_root(DEFAULT_ROOT);
+        table
+    }
+
+    /// Mounts `dir` at `/`, replacing the previous root.
+    pub fn set_root(&mut self, dir: &str) {
+        self.insert("/", dir);
+    }
+
+    /// Mounts `dir` at `prefix`, replacing any previous mount of the same prefix. The prefix may be
+    /// given with or without the leading and trailing `/`.
+    pub fn insert(&mut self, prefix: &str, dir: &str) {
+        let mount = Mount {
+            prefix: normalize_prefix(prefix),
+            dir: trim_dir(dir),
+        };
+        match self.mounts.iter_mut().find(|m| m.prefix == mount.prefix) {
+            Some(previous) => *previous = mount,
+            None => self.mounts.push(mount),
+        }
+        // longest prefixes first, so the first match is the most specific one
+        self.mounts
+            .sort_by_key(|mount| std::cmp::Reverse(mount.prefix.len()));
+    }
+
+    /// Iterates over the mounts as `(prefix, dir)` pairs, longest prefix first. Prefixes have no
+    /// trailing `/`, so the root mount has an empty one.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.mounts
+            .iter()
+            .map(|mount| (&mount.prefix[..], &mount.dir[..]))
+    }
+
+    /// Maps the decoded URL path `url_path`, which starts with `/`, to the filesystem. Returns
+    /// `None` if no mount serves it. The path is not checked against the filesystem, see
+    /// [`Resolved::allows`].
+    ///
+    /// # Examples
+    /// ```
+    /// use qst::mount::MountTable;
+    /// let mut table = MountTable::new();
+    /// table.set_root("dist/");
+    /// table.insert("/assets", "../shared/assets");
+    /// let resolve = |url_path| table.resolve(url_path).unwrap().path;
+    /// assert_eq!("dist/css/a.css", resolve("/css/a.css"));
+    /// assert_eq!("../shared/assets/logo.png", resolve("/assets/logo.png"));
+    /// assert_eq!("dist/assets-old/", resolve("/assets-old/"));
+    /// ```
+    pub fn resolve(&self, url_path: &str) -> Option<Resolved> {
+        self.mounts.iter().find_map(|mount| {
+            let rest = url_path.strip_prefix(&mount.prefix[..])?;
+            if !rest.is_empty() && !rest.starts_with('/') {
+                return None;
+            }
+            let path = if mount.dir == "/" {
+                format!("/{}", rest.trim_start_matches('/'))
+            } else {
+                format!("{}{rest}", mount.dir)
+            };
+            Some(Resolved {
+                prefix: mount.prefix.clone(),
+                dir: mount.dir.clone(),
+                path,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(table: &MountTable, url_path: &str) -> String {
+        table.resolve(url_path).unwrap().path
+    }
+
+    #[test]
+    fn default_table_serves_the_working_directory() {
+        let table = MountTable::new();
+        assert_eq!("./", resolve(&table, "/"));
+        assert_eq!("./css/style.css", resolve(&table, "/css/style.css"));
+    }
+
+    #[test]
+    fn longest_prefix_wins_on_whole_segments() {
+        let mut table = MountTable::new();
+        table.insert("assets/", "shared");
+        table.insert("/assets/img", "/srv/img/");
+        assert_eq!("shared", resolve(&table, "/assets"));
+        assert_eq!("shared/", resolve(&table, "/assets/"));
+        assert_eq!("/srv/img/a.png", resolve(&table, "/assets/img/a.png"));
+        assert_eq!("./assets-old/a.png", resolve(&table, "/assets-old/a.png"));
+
+        table.insert("/assets", "other");
+        assert_eq!("other/a.js", resolve(&table, "/assets/a.js"));
+    }
+
+    #[test]
+    fn table_without_root_serves_only_its_mounts() {
+        let mut table = MountTable { mounts: Vec::new() };
+        table.insert("/docs", "/");
+        assert_eq!("/etc", resolve(&table, "/docs/etc"));
+        assert_eq!(None, table.resolve("/etc"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_follow_the_policy() {
+        use std::os::unix::fs::symlink;
+        use std::path::Path;
+        let base = Path::new("target/qst-tests/symlinks");
+        let _ = fs::remove_dir_all(base);
+        fs::create_dir_all(base.join("root/sub")).unwrap();
+        fs::write(base.join("root/sub/a.js"), "a").unwrap();
+        fs::write(base.join("secret.txt"), "secret").unwrap();
+        symlink("sub/a.js", base.join("root/inside.js")).unwrap();
+        symlink("../secret.txt", base.join("root/outside.txt")).unwrap();
+        symlink("..", base.join("root/up")).unwrap();
+        let mut table = MountTable::new();
+        table.set_root("target/qst-tests/symlinks/root");
+        let allows = |url_path: &str, policy| {
+            let resolved = table.resolve(url_path).unwrap();
+            resolved.allows(&resolved.path, policy).ok()
+        };
+
+        for policy in [
+            SymlinkPolicy::Follow,
+            SymlinkPolicy::WithinRoot,
+            SymlinkPolicy::Deny,
+        ] {
+            assert_eq!(Some(true), allows("/sub/a.js", policy));
+            assert_eq!(Some(true), allows("/sub/", policy));
+            assert_eq!(None, allows("/missing.js", policy));
+        }
+
+        assert_eq!(Some(true), allows("/inside.js", SymlinkPolicy::Follow));
+        assert_eq!(Some(true), allows("/outside.txt", SymlinkPolicy::Follow));
+        assert_eq!(Some(true), allows("/up/secret.txt", SymlinkPolicy::Follow));
+
+        assert_eq!(Some(true), allows("/inside.js", SymlinkPolicy::WithinRoot));
+        assert_eq!(
+            Some(false),
+            allows("/outside.txt", SymlinkPolicy::WithinRoot)
+        );
+        assert_eq!(
+            Some(false),
+            allows("/up/secret.txt", SymlinkPolicy::WithinRoot)
+        );
+        assert_eq!(Some(false), allows("/up/", SymlinkPolicy::WithinRoot));
+
+        assert_eq!(Some(false), allows("/inside.js", SymlinkPolicy::Deny));
+        assert_eq!(Some(false), allows("/outside.txt", SymlinkPolicy::Deny));
+        assert_eq!(
+            Some(false),
+            allows("/up/root/sub/a.js", SymlinkPolicy::Deny)
+        );
+    }
+}
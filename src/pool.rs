@@ -0,0 +1,312 @@
+use std::collections::VecDeque;
+use std::io::Result as IoResult;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Number of jobs that may wait for a worker before [`ThreadPool::execute`] blocks.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 128;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A snapshot of the state of a [`ThreadPool`].
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct PoolStats {
+    /// Threads spawned by the pool.
+    pub workers: usize,
+    /// Workers currently running a job.
+    pub busy: usize,
+    /// Jobs waiting in the queue for a free worker.
+    pub queued: usize,
+    /// Jobs that already finished.
+    pub completed: u64,
+}
+
+struct State {
+    jobs: VecDeque<Job>,
+    idle: usize,
+    busy: usize,
+    completed: u64,
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    job_ready: Condvar,
+    space_ready: Condvar,
+    all_done: Condvar,
+    capacity: usize,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // jobs run outside the lock, so it can't be poisoned by them
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// A pool of reusable worker threads fed by a bounded job queue.
+///
+/// Workers are spawned lazily, when a job arrives and no worker is idle, up to `max_workers`.
+/// When every worker is busy, jobs wait in the queue. When the queue is full, [`execute`] blocks
+/// until a worker takes a job, so a flood of connections can't exhaust the memory.
+///
+/// Dropping the pool shuts it down: the queue is drained and every worker joined.
+///
+/// [`execute`]: ThreadPool::execute
+///
+/// # Examples
+/// ```
+/// use qst::pool::ThreadPool;
+/// use std::sync::mpsc;
+///
+/// let mut pool = ThreadPool::new(Some(2), 8);
+/// let (tx, rx) = mpsc::channel();
+/// for i in 0..4 {
+///     let tx = tx.clone();
+///     pool.execute(move || tx.send(i * i).unwrap()).unwrap();
+/// }
+/// pool.shutdown();
+/// assert_eq!(14, rx.try_iter().sum::<i32>());
+/// ```
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+    max_workers: Option<usize>,
+}
+
+impl ThreadPool {
+    /// Creates an empty pool. `max_workers` of `None` means no limit on the number of threads.
+    /// A `queue_capacity` of 0 is raised to 1.
+    pub fn new(max_workers: Option<usize>, queue_capacity: usize) -> ThreadPool {
+        ThreadPool {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    jobs: VecDeque::new(),
+                    idle: 0,
+                    busy: 0,
+                    completed: 0,
+                    closed: false,
+                }),
+                job_ready: Condvar::new(),
+                space_ready: Condvar::new(),
+                all_done: Condvar::new(),
+                capacity: queue_capacity.max(1),
+            }),
+            workers: Vec::new(),
+            max_workers,
+        }
+    }
+
+    /// Queues a job, spawning a new worker if none is free and the limit allows it. Blocks while
+    /// the queue is full. Returns `Err` only if no worker exists and none could be spawned, in
+    /// which case the job is dropped.
+    pub fn execute<F>(&mut self, job: F) -> IoResult<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.shared.lock();
+        while state.jobs.len() >= self.shared.capacity {
+            state = self
+                .shared
+                .space_ready
+                .wait(state)
+                .unwrap_or_else(|err| err.into_inner());
+        }
+        state.jobs.push_back(Box::new(job));
+        let below_max = match self.max_workers {
+            Some(max) => self.workers.len() < max,
+            None => true,
+        };
+        let needs_worker = state.jobs.len() > state.idle && below_max;
+        drop(state);
+        self.shared.job_ready.notify_one();
+
+        if needs_worker {
+            if let Err(err) = self.spawn_worker() {
+                // the queued job will be taken by one of the existing workers
+                if self.workers.is_empty() {
+                    self.shared.lock().jobs.clear();
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn spawn_worker(&mut self) -> IoResult<()> {
+        let shared = Arc::clone(&self.shared);
+        let handle = thread::Builder::new()
+            .name(format!("qst-worker-{}", self.workers.len()))
+            .spawn(move || work(shared))?;
+        self.workers.push(handle);
+        Ok(())
+    }
+
+    /// Returns a snapshot of the pool state.
+    pub fn stats(&self) -> PoolStats {
+        let state = self.shared.lock();
+        PoolStats {
+            workers: self.workers.len(),
+            busy: state.busy,
+            queued: state.jobs.len(),
+            completed: state.completed,
+        }
+    }
+
+    /// Waits until no job is queued or running, for at most `timeout`. Returns whether the pool
+    /// became idle in time.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.lock();
+        while state.busy > 0 || !state.jobs.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self
+                .shared
+                .all_done
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|err| err.into_inner())
+                .0;
+        }
+        true
+    }
+
+    /// Stops accepting jobs, waits for the queued and running ones to finish and joins every
+    /// worker. Calling it more than once is harmless.
+    pub fn shutdown(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.job_ready.notify_all();
+        for worker in self.workers.drain(..) {
+            // a worker only panics on a poisoned lock, nothing to recover then
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn work(shared: Arc<Shared>) {
+    loop {
+        let job = {
+            let mut state = shared.lock();
+            loop {
+                if let Some(job) = state.jobs.pop_front() {
+                    state.busy += 1;
+                    break job;
+                }
+                if state.closed {
+                    return;
+                }
+                state.idle += 1;
+                state = shared
+                    .job_ready
+                    .wait(state)
+                    .unwrap_or_else(|err| err.into_inner());
+                state.idle -= 1;
+            }
+        };
+        shared.space_ready.notify_one();
+
+        // a panicking job must not take the worker down with it
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+
+        let mut state = shared.lock();
+        state.busy -= 1;
+        state.completed += 1;
+        if state.busy == 0 && state.jobs.is_empty() {
+            shared.all_done.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn pool_reuses_threads_up_to_max() {
+        let mut pool = ThreadPool::new(Some(2), 16);
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..10 {
+            let tx = tx.clone();
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(5));
+                tx.send(thread::current().id()).unwrap();
+            })
+            .unwrap();
+        }
+        pool.shutdown();
+
+        let ids: Vec<_> = rx.try_iter().collect();
+        assert_eq!(10, ids.len());
+        assert!(ids.iter().collect::<HashSet<_>>().len() <= 2);
+        assert_eq!(0, pool.stats().workers);
+    }
+
+    #[test]
+    fn pool_reports_queue_depth() {
+        let mut pool = ThreadPool::new(Some(1), 4);
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let (started_tx, started_rx) = mpsc::channel();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        })
+        .unwrap();
+        started_rx.recv().unwrap();
+        for _ in 0..3 {
+            pool.execute(|| {}).unwrap();
+        }
+
+        assert_eq!(
+            PoolStats {
+                workers: 1,
+                busy: 1,
+                queued: 3,
+                completed: 0,
+            },
+            pool.stats()
+        );
+
+        release_tx.send(()).unwrap();
+        pool.shutdown();
+        assert_eq!(4, pool.stats().completed);
+        assert_eq!(0, pool.stats().queued);
+    }
+
+    #[test]
+    fn pool_waits_for_idle_until_timeout() {
+        let mut pool = ThreadPool::new(None, 4);
+        assert!(pool.wait_idle(Duration::ZERO));
+
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        pool.execute(move || release_rx.recv().unwrap()).unwrap();
+        assert!(!pool.wait_idle(Duration::from_millis(50)));
+
+        release_tx.send(()).unwrap();
+        assert!(pool.wait_idle(Duration::from_secs(5)));
+        assert_eq!(1, pool.stats().completed);
+    }
+
+    #[test]
+    fn pool_survives_panicking_jobs() {
+        let mut pool = ThreadPool::new(Some(1), 4);
+        let (tx, rx) = mpsc::channel();
+        pool.execute(|| panic!("job failed")).unwrap();
+        pool.execute(move || tx.send(42).unwrap()).unwrap();
+        assert_eq!(42, rx.recv_timeout(Duration::from_secs(5)).unwrap());
+        pool.shutdown();
+        assert_eq!(2, pool.stats().completed);
+    }
+}
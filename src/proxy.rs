@@ -0,0 +1,544 @@
+use crate::body::{Body, BUFFER_SIZE};
+use crate::http::{
+    self, Framing, Headers, HttpMethod, HttpRequest, HttpResponse, HttpResponseCode, HttpVersion,
+};
+use crate::url;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Result as IoResult};
+use std::iter;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// How long connecting to an upstream may take before the client gets a 502.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long an upstream may stay silent before the client gets a 504, or the response is cut.
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Fields that only concern a single connection and are never forwarded (RFC 9110, section 7.6.1),
+/// along with the ones listed in `Connection`.
+const HOP_BY_HOP: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// A server requests are forwarded to.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Upstream {
+    pub host: String,
+    pub port: u16,
+    /// Path replacing the prefix of the rule, without the trailing `/`. When empty, paths are
+    /// forwarded as they are, prefix included.
+    pub path: String,
+}
+
+impl Upstream {
+    /// Parses an upstream URL, as `http://host[:port][/path]`. Only plain HTTP is supported.
+    ///
+    /// # Examples
+    /// ```
+    /// use qst::proxy::Upstream;
+    /// let upstream = Upstream::parse("http://127.0.0.1:8080/v1/").unwrap();
+    /// assert_eq!(("127.0.0.1", 8080, "/v1"), (&upstream.host[..], upstream.port, &upstream.path[..]));
+    /// assert_eq!(80, Upstream::parse("http://localhost").unwrap().port);
+    /// assert_eq!(None, Upstream::parse("https://localhost"));
+    /// ```
+    pub fn parse(url: &str) -> Option<Upstream> {
+        let rest = url.strip_prefix("http://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+        // IPv6 addresses keep their brackets, as in the Host field
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+            _ => (authority, 80),
+        };
+        if host.is_empty() || path.contains(['?', '#']) {
+            return None;
+        }
+        Some(Upstream {
+            host: host.to_string(),
+            port,
+            path: path.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// Value of the `Host` field sent to the upstream.
+    pub fn authority(&self) -> String {
+        if self.port == 80 {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct Rule {
+    /// URL prefix without the trailing `/`, empty to proxy everything.
+    prefix: String,
+    upstream: Upstream,
+}
+
+/// A request matched by a rule of a [`ProxyTable`].
+#[derive(Debug, PartialEq, Clone)]
+pub struct Route<'a> {
+    pub prefix: &'a str,
+    pub upstream: &'a Upstream,
+    /// Request target to send to the upstream, query included.
+    pub target: String,
+}
+
+/// Maps URL prefixes to the upstreams requests under them are forwarded to. Like mounts, prefixes
+/// match whole path segments and the longest one wins.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ProxyTable {
+    rules: Vec<Rule>,
+}
+
+impl ProxyTable {
+    /// Creates an empty table, which forwards nothing.
+    pub fn new() -> ProxyTable {
+        ProxyTable::default()
+    }
+
+    /// Forwards the requests under `prefix` to `upstream`, replacing any previous rule of the same
+    /// prefix.
+    pub fn insert(&mut self, prefix: &str, upstream: Upstream) {
+        let rule = Rule {
+            prefix: crate::mount::normalize_prefix(prefix),
+            upstream,
+        };
+        match self.rules.iter_mut().find(|r| r.prefix == rule.prefix) {
+            Some(previous) => *previous = rule,
+            None => self.rules.push(rule),
+        }
+        self.rules
+            .sort_by_key(|rule| std::cmp::Reverse(rule.prefix.len()));
+    }
+
+    /// Finds the rule forwarding a request. The prefix is matched on the decoded URL `path`,
+    /// checked like [`HttpRequest::match_fetch`] does, but the upstream gets the rest of the
+    /// `raw_path` and the `query` as they were sent. A prefix only encoded differently in the
+    /// raw path, like `/api%2Fusers` for `/api`, matches nothing.
+    ///
+    /// # Examples
+    /// ```
+    /// use qst::proxy::{ProxyTable, Upstream};
+    /// let mut table = ProxyTable::new();
+    /// table.insert("/api", Upstream::parse("http://127.0.0.1:8080").unwrap());
+    /// table.insert("/auth", Upstream::parse("http://127.0.0.1:9000/v2").unwrap());
+    /// let route = table.find("/api/users", "/api/users", Some("page=2")).unwrap();
+    /// assert_eq!("/api/users?page=2", route.target);
+    /// let route = table.find("/auth/a/b", "/%61uth/a%2Fb", None).unwrap();
+    /// assert_eq!("/v2/a%2Fb", route.target);
+    /// assert_eq!(None, table.find("/apis", "/apis", None));
+    /// ```
+    pub fn find(&self, path: &str, raw_path: &str, query: Option<&str>) -> Option<Route<'_>> {
+        self.rules.iter().find_map(|rule| {
+            let rest = path.strip_prefix(&rule.prefix[..])?;
+            if !rest.is_empty() && !rest.starts_with('/') {
+                return None;
+            }
+            // the prefix spans as many segments in the raw path, which must decode to it
+            let slashes = rule.prefix.matches('/').count();
+            let end = match raw_path.match_indices('/').nth(slashes) {
+                Some((end, _)) => end,
+                None => raw_path.len(),
+            };
+            if url::percent_decode(&raw_path[..end], false)? != rule.prefix {
+                return None;
+            }
+            // the prefix is kept as configured, the upstream may not decode its variants
+            let mut target = if rule.upstream.path.is_empty() {
+                format!("{}{}", rule.prefix, &raw_path[end..])
+            } else {
+                format!("{}{}", rule.upstream.path, &raw_path[end..])
+            };
+            if !target.starts_with('/') {
+                target.insert(0, '/');
+            }
+            if let Some(query) = query {
+                target.push('?');
+                target.push_str(query);
+            }
+            Some(Route {
+                prefix: &rule.prefix,
+                upstream: &rule.upstream,
+                target,
+            })
+        })
+    }
+}
+
+fn response(code: HttpResponseCode) -> HttpResponse {
+    HttpResponse {
+        code,
+        content: None,
+        content_length: None,
+        headers: Headers::new(),
+    }
+}
+
+/// Whether the field `name` must not be forwarded, given the `Connection` of its message.
+fn is_hop_by_hop(name: &str, headers: &Headers) -> bool {
+    HOP_BY_HOP
+        .iter()
+        .any(|field| field.eq_ignore_ascii_case(name))
+        || headers
+            .get_list("Connection")
+            .any(|field| field.eq_ignore_ascii_case(name))
+}
+
+/// Maps a `Location` sent by the upstream to the URL the client knows: absolute URLs of the
+/// upstream are moved to `client_host`, or made relative without it, and its path gets the prefix
+/// of the rule back. Locations elsewhere are kept.
+fn rewrite_location(location: &str, route: &Route, client_host: Option<&str>) -> String {
+    let origin = format!("http://{}", route.upstream.authority());
+    let (origin, path) = match location.get(..origin.len()) {
+        Some(start) if start.eq_ignore_ascii_case(&origin) => {
+            let path = &location[origin.len()..];
+            if !path.is_empty() && !path.starts_with(['/', '?', '#']) {
+                return location.to_string();
+            }
+            let client_origin = client_host.map(|host| format!("http://{host}"));
+            (client_origin.unwrap_or_default(), path)
+        }
+        _ if location.starts_with('/') && !location.starts_with("//") => (String::new(), location),
+        _ => return location.to_string(),
+    };
+    let base = &route.upstream.path;
+    let path = match path.strip_prefix(&base[..]) {
+        Some(rest)
+            if !base.is_empty() && (rest.is_empty() || rest.starts_with(['/', '?', '#'])) =>
+        {
+            format!("{}{rest}", route.prefix)
+        }
+        _ => path.to_string(),
+    };
+    let path = if path.is_empty() || !path.starts_with('/') {
+        format!("/{path}")
+    } else {
+        path
+    };
+    format!("{origin}{path}")
+}
+
+/// Streams exactly `len` bytes of `reader`, failing if it ends before.
+fn read_exactly(mut reader: impl Read + Send + 'static, len: u64) -> Body {
+    let mut remaining = len;
+    Body::Chunks(Box::new(iter::from_fn(move || {
+        if remaining == 0 {
+            return None;
+        }
+        let mut buffer = vec![0; remaining.min(BUFFER_SIZE as u64) as usize];
+        match reader.read(&mut buffer) {
+            Ok(0) => {
+                remaining = 0;
+                Some(Err(ErrorKind::UnexpectedEof.into()))
+            }
+            Ok(n) => {
+                remaining -= n as u64;
+                buffer.truncate(n);
+                Some(Ok(buffer))
+            }
+            Err(err) => {
+                remaining = 0;
+                Some(Err(err))
+            }
+        }
+    })))
+}
+
+/// Streams `reader` until it ends.
+fn read_to_end(mut reader: impl Read + Send + 'static) -> Body {
+    let mut done = false;
+    Body::Chunks(Box::new(iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        let mut buffer = vec![0; BUFFER_SIZE];
+        match reader.read(&mut buffer) {
+            Ok(0) => None,
+            Ok(n) => {
+                buffer.truncate(n);
+                Some(Ok(buffer))
+            }
+            Err(err) => {
+                done = true;
+                Some(Err(err))
+            }
+        }
+    })))
+}
+
+fn connect(upstream: &Upstream) -> IoResult<TcpStream> {
+    let mut last_err = io::Error::from(ErrorKind::NotFound);
+    let host = upstream.host.trim_matches(['[', ']']);
+    for addr in (host, upstream.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+/// Reads the status line and the header section of the upstream response, skipping interim 1xx
+/// responses. Fails with the response to send the client if the upstream answers nonsense or is
+/// too slow.
+fn read_head(reader: &mut impl BufRead) -> Result<(u16, String, Headers), HttpResponse> {
+    let failure = |code: HttpResponseCode| match code {
+        HttpResponseCode::RequestTimeout408 => response(HttpResponseCode::GatewayTimeout504),
+        _ => response(HttpResponseCode::BadGateway502),
+    };
+    loop {
+        let mut lines = reader.by_ref().lines();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(err)) => return Err(failure(HttpResponse::from_read_error(err).code)),
+            None => return Err(failure(HttpResponseCode::BadGateway502)),
+        };
+        let mut parts = line.splitn(3, ' ');
+        let status = match (parts.next(), parts.next()) {
+            (Some(version), Some(status)) if version.starts_with("HTTP/1.") => status,
+            _ => return Err(failure(HttpResponseCode::BadGateway502)),
+        };
+        let status = match status.parse::<u16>() {
+            Ok(code) if status.len() == 3 && (100..600).contains(&code) => code,
+            _ => return Err(failure(HttpResponseCode::BadGateway502)),
+        };
+        let reason = parts.next().unwrap_or("").to_string();
+        let headers = Headers::read_section(lines).map_err(|response| failure(response.code))?;
+        if status >= 200 {
+            return Ok((status, reason, headers));
+        }
+    }
+}
+
+/// Forwards `request` along `route`, with the body already read. The request goes out with `Host`
+/// set to the upstream and `X-Forwarded-For`, `X-Forwarded-Host` and `X-Forwarded-Proto` telling
+/// about the client at `peer`. The upstream response is streamed back as it arrives, with its
+/// `Location` rewritten.
+///
+/// Requests go out as HTTP/1.0 on a new connection, like most proxies do, and are written like
+/// [`HttpRequest::write_to`] does, so bodies are sent with a `Content-Length` whatever their
+/// framing was. That body was read in full beforehand, within the size limit of the server. The
+/// response is framed like requests are, see [`HttpRequest::read_body`], but without a limit and
+/// until the connection closes when it announces no framing.
+///
+/// The client gets a 502 if the upstream can't be reached or answers nonsense, and a 504 if it is
+/// too slow.
+pub fn forward(request: HttpRequest, route: &Route, peer: Option<SocketAddr>) -> HttpResponse {
+    let upstream = match connect(route.upstream) {
+        Ok(upstream) => upstream,
+        Err(_) => return response(HttpResponseCode::BadGateway502),
+    };
+    if upstream.set_read_timeout(Some(UPSTREAM_TIMEOUT)).is_err()
+        || upstream.set_write_timeout(Some(UPSTREAM_TIMEOUT)).is_err()
+    {
+        return response(HttpResponseCode::BadGateway502);
+    }
+
+    let mut headers = Headers::new();
+    for (name, value) in request.headers.iter() {
+        // set below, or answered by the server already
+        let replaced = [
+            "Expect",
+            "Host",
+            "X-Forwarded-For",
+            "X-Forwarded-Host",
+            "X-Forwarded-Proto",
+        ]
+        .iter()
+        .any(|field| field.eq_ignore_ascii_case(name));
+        if !replaced && !is_hop_by_hop(name, &request.headers) {
+            headers.append(name, value);
+        }
+    }
+    headers.append("Host", &route.upstream.authority());
+    let mut forwarded_for: Vec<&str> = request.headers.get_list("X-Forwarded-For").collect();
+    let peer_ip = peer.map(|peer| peer.ip().to_string());
+    forwarded_for.extend(peer_ip.as_deref());
+    if !forwarded_for.is_empty() {
+        headers.append("X-Forwarded-For", &forwarded_for.join(", "));
+    }
+    let client_host = request.headers.get("Host").map(str::to_string);
+    if let Some(host) = &client_host {
+        headers.append("X-Forwarded-Host", host);
+    }
+    headers.append("X-Forwarded-Proto", "http");
+    headers.append("Connection", "close");
+
+    let method = request.method;
+    let upstream_request = HttpRequest {
+        method,
+        fetch: route.target.clone(),
+        query: request.query,
+        version: HttpVersion::Http10,
+        headers,
+        body: request.body,
+        trailers: Headers::new(),
+    };
+    if upstream_request.write_to(&mut &upstream).is_err() {
+        return response(HttpResponseCode::BadGateway502);
+    }
+
+    let mut reader = BufReader::new(upstream);
+    let (status, reason, upstream_headers) = match read_head(&mut reader) {
+        Ok(head) => head,
+        Err(response) => return response,
+    };
+    let framing = match Framing::of(&upstream_headers) {
+        Ok(framing) => framing,
+        Err(_) => return response(HttpResponseCode::BadGateway502),
+    };
+
+    let mut headers = Headers::new();
+    for (name, value) in upstream_headers.iter() {
+        if name.eq_ignore_ascii_case("Content-Length") || is_hop_by_hop(name, &upstream_headers) {
+            continue;
+        }
+        if name.eq_ignore_ascii_case("Location") {
+            let location = rewrite_location(value, route, client_host.as_deref());
+            headers.append(name, &location);
+        } else {
+            headers.append(name, value);
+        }
+    }
+
+    let code = HttpResponseCode::from_status(status, &reason);
+    let length = match framing {
+        Some(Framing::Length(len)) => Some(len),
+        _ => None,
+    };
+    let (content, content_length) = if method == HttpMethod::HEAD || !code.allows_content() {
+        (None, length.filter(|_| method == HttpMethod::HEAD))
+    } else {
+        match framing {
+            Some(Framing::Length(len)) => (Some(read_exactly(reader, len)), Some(len)),
+            Some(Framing::Chunked) => (Some(Body::Chunks(http::stream_chunked(reader))), None),
+            None => (Some(read_to_end(reader)), None),
+        }
+    };
+    HttpResponse {
+        code,
+        content,
+        content_length: content_length.map(|len| len as usize),
+        headers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> ProxyTable {
+        let mut table = ProxyTable::new();
+        table.insert("/api/", Upstream::parse("http://127.0.0.1:8080").unwrap());
+        table.insert("/auth", Upstream::parse("http://auth.local/v2/").unwrap());
+        table
+    }
+
+    #[test]
+    fn upstreams_are_parsed() {
+        let upstream = Upstream::parse("http://[::1]:3000").unwrap();
+        assert_eq!(("[::1]", 3000), (&upstream.host[..], upstream.port));
+        assert_eq!("[::1]:3000", upstream.authority());
+        assert_eq!(
+            "auth.local",
+            Upstream::parse("http://auth.local/").unwrap().authority()
+        );
+        for url in [
+            "127.0.0.1:8080",
+            "http://",
+            "http://host:port",
+            "http://host/a?b",
+        ] {
+            assert_eq!(None, Upstream::parse(url), "{url}");
+        }
+    }
+
+    #[test]
+    fn rules_map_targets() {
+        let table = table();
+        let target = |fetch: &str| {
+            let (raw_path, query) = url::split_target(fetch);
+            let path = url::percent_decode(raw_path, false).unwrap();
+            table.find(&path, raw_path, query).map(|route| route.target)
+        };
+        assert_eq!(Some(String::from("/api")), target("/api"));
+        assert_eq!(
+            Some(String::from("/api/a%20b?x=1")),
+            target("/api/a%20b?x=1")
+        );
+        // the rest of the path goes upstream as it was sent
+        assert_eq!(
+            Some(String::from("/api/files/a%2Fb/caf%C3%A9/(x)")),
+            target("/%61pi/files/a%2Fb/caf%C3%A9/(x)")
+        );
+        assert_eq!(Some(String::from("/v2/a%2fb")), target("/auth/a%2fb"));
+        // a prefix must be made of the same segments in the raw path
+        assert_eq!(None, target("/api%2Fusers"));
+        assert_eq!(Some(String::from("/v2")), target("/auth"));
+        assert_eq!(Some(String::from("/v2/?next=/")), target("/auth/?next=/"));
+        assert_eq!(None, target("/"));
+        assert_eq!(None, target("/authors"));
+    }
+
+    #[test]
+    fn locations_point_back_to_the_proxy() {
+        let table = table();
+        let api = table.find("/api", "/api", None).unwrap();
+        let auth = table.find("/auth", "/auth", None).unwrap();
+        let host = Some("localhost:6969");
+        assert_eq!(
+            "http://localhost:6969/api/users/1",
+            rewrite_location("http://127.0.0.1:8080/api/users/1", &api, host)
+        );
+        assert_eq!("/", rewrite_location("http://127.0.0.1:8080", &api, None));
+        assert_eq!("/api/users", rewrite_location("/api/users", &api, host));
+        assert_eq!(
+            "http://localhost:6969/auth/done?x=1",
+            rewrite_location("http://AUTH.local/v2/done?x=1", &auth, host)
+        );
+        assert_eq!("/auth", rewrite_location("/v2", &auth, host));
+        assert_eq!("/v20", rewrite_location("/v20", &auth, host));
+        for location in [
+            "https://example.com/v2",
+            "//example.com/v2",
+            "http://auth.local.evil/v2",
+            "done",
+        ] {
+            assert_eq!(location, rewrite_location(location, &auth, host));
+        }
+    }
+
+    #[test]
+    fn upstream_heads_are_parsed() {
+        let mut wire =
+            &b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.0 201 Created\r\nLocation: /a\nX: y\r\n\r\nbody"
+                [..];
+        let (status, reason, headers) = read_head(&mut wire).unwrap();
+        assert_eq!((201, "Created"), (status, &reason[..]));
+        assert_eq!(Some("/a"), headers.get("Location"));
+        assert_eq!(b"body", wire);
+
+        for wire in [
+            "HTTP/1.1 20 Short\r\n\r\n",
+            "HTTP/2 200 OK\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nbad\r\n\r\n",
+        ] {
+            let code = read_head(&mut wire.as_bytes()).unwrap_err().code;
+            assert_eq!(HttpResponseCode::BadGateway502, code, "{wire}");
+        }
+        assert!(read_head(&mut &b""[..]).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use crate::body::Body;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Maximum number of ranges served in a single response. Requests asking for more get the whole
+/// file instead, as many tiny ranges cost more than the file itself.
+pub const MAX_RANGES: usize = 32;
+
+/// A range of bytes of a file, both ends included.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ByteRange {
+    pub first: u64,
+    pub last: u64,
+}
+
+impl ByteRange {
+    /// Number of bytes in the range.
+    pub fn size(&self) -> u64 {
+        self.last - self.first + 1
+    }
+
+    /// Value of the `Content-Range` header for this range of a file of `total` bytes.
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{total}", self.first, self.last)
+    }
+}
+
+/// What a `Range` header asks for.
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    /// The header is invalid, uses another unit or asks for too many ranges, so it is ignored and
+    /// the whole file is sent.
+    Whole,
+    /// The satisfiable ranges, in the order asked.
+    Partial(Vec<ByteRange>),
+    /// No range overlaps the file, which calls for a 416.
+    Unsatisfiable,
+}
+
+/// Parses the value of a `Range` header (RFC 9110, section 14.2) for a file of `total` bytes.
+/// Ranges past the end are clipped and the ones starting after it are dropped.
+///
+/// # Examples
+/// ```
+/// use qst::range::{parse, ByteRange, RangeRequest};
+/// assert_eq!(
+///     RangeRequest::Partial(vec![
+///         ByteRange { first: 0, last: 9 },
+///         ByteRange { first: 90, last: 99 },
+///     ]),
+///     parse("bytes=0-9, -10", 100)
+/// );
+/// assert_eq!(RangeRequest::Unsatisfiable, parse("bytes=100-", 100));
+/// assert_eq!(RangeRequest::Whole, parse("lines=1-2", 100));
+/// ```
+pub fn parse(header: &str, total: u64) -> RangeRequest {
+    let specs = match header.trim().split_once('=') {
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return RangeRequest::Whole,
+    };
+
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+    {
+        count += 1;
+        if count > MAX_RANGES {
+            return RangeRequest::Whole;
+        }
+        let (first, last) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return RangeRequest::Whole,
+        };
+        let digits = |text: &str| !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit());
+        let range = if first.is_empty() {
+            // suffix range: the last bytes of the file
+            if !digits(last) {
+                return RangeRequest::Whole;
+            }
+            match last.parse::<u64>() {
+                Ok(0) => None,
+                Ok(suffix) if total > 0 => Some(ByteRange {
+                    first: total.saturating_sub(suffix),
+                    last: total - 1,
+                }),
+                Ok(_) => None,
+                // more digits than any file could have
+                Err(_) if total > 0 => Some(ByteRange {
+                    first: 0,
+                    last: total - 1,
+                }),
+                Err(_) => None,
+            }
+        } else {
+            if !digits(first) || !(last.is_empty() || digits(last)) {
+                return RangeRequest::Whole;
+            }
+            let first = first.parse::<u64>().unwrap_or(u64::MAX);
+            let last = match last {
+                "" => u64::MAX,
+                last => last.parse::<u64>().unwrap_or(u64::MAX),
+            };
+            if last < first {
+                return RangeRequest::Whole;
+            }
+            if first < total {
+                Some(ByteRange {
+                    first,
+                    last: last.min(total - 1),
+                })
+            } else {
+                None
+            }
+        };
+        ranges.extend(range);
+    }
+
+    if count == 0 {
+        RangeRequest::Whole
+    } else if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(ranges)
+    }
+}
+
+/// Returns a new multipart boundary. It only has to be unlikely to appear in the files, so the
+/// clock and a counter are enough.
+pub fn new_boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("qst-{nanos:x}-{count:x}")
+}
+
+/// Assembles a `multipart/byteranges` body out of the ranges of a file of `total` bytes and their
+/// contents, streamed one after the other. Returns the body and its size, known if the size of
+/// every part is. The response must be sent with `multipart/byteranges; boundary={boundary}` as
+/// its `Content-Type`.
+pub fn multipart_body(
+    boundary: &str,
+    content_type: &str,
+    total: u64,
+    parts: Vec<(ByteRange, Body)>,
+) -> (Body, Option<u64>) {
+    let mut size = Some(0);
+    let mut pieces = Vec::with_capacity(2 * parts.len() + 1);
+    for (range, content) in parts {
+        let header = format!(
+            "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+            range.content_range(total)
+        );
+        size = match (size, content.size()) {
+            (Some(size), Some(content_size)) => Some(size + header.len() as u64 + content_size),
+            _ => None,
+        };
+        pieces.push(Body::Bytes(header.into_bytes()));
+        pieces.push(content);
+    }
+    let closing = format!("\r\n--{boundary}--\r\n");
+    size = size.map(|size| size + closing.len() as u64);
+    pieces.push(Body::Bytes(closing.into_bytes()));
+
+    let chunks = pieces.into_iter().flat_map(Body::into_chunks);
+    (Body::Chunks(Box::new(chunks)), size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(ranges: &[(u64, u64)]) -> RangeRequest {
+        RangeRequest::Partial(
+            ranges
+                .iter()
+                .map(|&(first, last)| ByteRange { first, last })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn parses_satisfiable_ranges() {
+        assert_eq!(partial(&[(0, 0)]), parse("bytes=0-0", 10));
+        assert_eq!(partial(&[(5, 9)]), parse("bytes=5-", 10));
+        assert_eq!(partial(&[(5, 9)]), parse("bytes=5-100", 10));
+        assert_eq!(partial(&[(0, 9)]), parse("bytes=-100", 10));
+        assert_eq!(
+            partial(&[(0, 9)]),
+            parse("bytes=-99999999999999999999999", 10)
+        );
+        assert_eq!(partial(&[(8, 9), (0, 1)]), parse("Bytes = -2 ,0-1,", 10));
+        // unsatisfiable ones are dropped as long as one remains
+        assert_eq!(partial(&[(2, 3)]), parse("bytes=20-30,2-3,-0", 10));
+    }
+
+    #[test]
+    fn rejects_invalid_or_unsatisfiable_ranges() {
+        for header in [
+            "bytes",
+            "bytes=",
+            "bytes=abc",
+            "bytes=5-2",
+            "bytes=-",
+            "bytes=1-2-3",
+            "bytes=+1-2",
+            "items=0-1",
+        ] {
+            assert_eq!(RangeRequest::Whole, parse(header, 10), "{header}");
+        }
+        let too_many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(RangeRequest::Whole, parse(&too_many, 10));
+
+        assert_eq!(RangeRequest::Unsatisfiable, parse("bytes=10-", 10));
+        assert_eq!(RangeRequest::Unsatisfiable, parse("bytes=-0", 10));
+        assert_eq!(RangeRequest::Unsatisfiable, parse("bytes=0-", 0));
+        assert_eq!(RangeRequest::Unsatisfiable, parse("bytes=-5", 0));
+    }
+
+    #[test]
+    fn assembles_multipart_bodies() {
+        let parts = vec![
+            (ByteRange { first: 0, last: 1 }, Body::Bytes(b"ab".to_vec())),
+            (ByteRange { first: 8, last: 9 }, Body::Bytes(b"ij".to_vec())),
+        ];
+        let (body, size) = multipart_body("sep", "text/plain", 10, parts);
+        let body = String::from_utf8(body.into_bytes().unwrap()).unwrap();
+        assert_eq!(Some(body.len() as u64), size);
+        assert_eq!(
+            "\r\n--sep\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\nab\
+\r\n--sep\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\nij\
+\r\n--sep--\r\n",
+            body
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+static SIGNALED: AtomicBool = AtomicBool::new(false);
+
+/// Outcome of a server shutdown.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct ShutdownReport {
+    /// Connections that were answered.
+    pub completed: u64,
+    /// Connections that were still in flight when the shutdown deadline was reached and had to be
+    /// closed mid-request.
+    pub cut_off: usize,
+    /// Connections that failed on the client side, for instance by being reset mid-response.
+    pub connection_errors: u64,
+}
+
+/// Asks a running server to stop. The server stops accepting connections, lets the ones in
+/// flight finish until `Config::shutdown_timeout` and then returns a [`ShutdownReport`].
+///
+/// Handles are cheap to clone and may be sent to other threads.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    on_signals: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> ShutdownHandle {
+        ShutdownHandle::default()
+    }
+
+    /// Requests the shutdown. Returns immediately, the server stops on its own thread.
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the shutdown was requested, either through [`ShutdownHandle::shutdown`] or by a
+    /// signal after [`ShutdownHandle::listen_for_signals`].
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+            || (self.on_signals.load(Ordering::SeqCst) && SIGNALED.load(Ordering::SeqCst))
+    }
+
+    /// Makes SIGINT and SIGTERM request the shutdown. A second signal exits the process right
+    /// away. Does nothing on platforms without signals.
+    pub fn listen_for_signals(&self) {
+        self.on_signals.store(true, Ordering::SeqCst);
+        signals::install();
+    }
+}
+
+#[cfg(unix)]
+mod signals {
+    use super::SIGNALED;
+    use std::os::raw::c_int;
+    use std::sync::atomic::Ordering;
+
+    const SIGINT: c_int = 2;
+    const SIGTERM: c_int = 15;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+        fn _exit(status: c_int) -> !;
+    }
+
+    extern "C" fn on_signal(signum: c_int) {
+        // only async-signal-safe calls in here
+        if SIGNALED.swap(true, Ordering::SeqCst) {
+            unsafe { _exit(128 + signum) }
+        }
+    }
+
+    pub fn install() {
+        let handler = on_signal as extern "C" fn(c_int) as usize;
+        unsafe {
+            signal(SIGINT, handler);
+            signal(SIGTERM, handler);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod signals {
+    pub fn install() {}
+}
+
+struct Tracked {
+    stream: TcpStream,
+    busy: bool,
+}
+
+/// Keeps track of the open connections so they can be closed on shutdown: idle persistent
+/// connections as soon as the server starts draining, busy ones when the deadline is reached.
+#[derive(Default)]
+pub(crate) struct ConnectionTracker {
+    connections: Mutex<HashMap<u64, Tracked>>,
+    next_id: AtomicU64,
+    draining: AtomicBool,
+    cut: AtomicBool,
+    cut_off: AtomicUsize,
+    errors: AtomicU64,
+    responses: AtomicUsize,
+}
+
+impl ConnectionTracker {
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, Tracked>> {
+        self.connections
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Starts tracking a connection, initially busy. Returns `None` if the server already cut
+    /// the connections off, in which case this one is counted as cut off too.
+    pub(crate) fn register(&self, stream: &TcpStream) -> Option<Connection<'_>> {
+        let mut connections = self.lock();
+        if self.cut.load(Ordering::SeqCst) {
+            self.cut_off.fetch_add(1, Ordering::SeqCst);
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        // without a handle to it, the connection just can't be closed early
+        if let Ok(stream) = stream.try_clone() {
+            connections.insert(id, Tracked { stream, busy: true });
+        }
+        Some(Connection { tracker: self, id })
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn is_cut(&self) -> bool {
+        self.cut.load(Ordering::SeqCst)
+    }
+
+    /// Stops persistent connections: idle ones are closed now, busy ones after their response.
+    pub(crate) fn start_draining(&self) {
+        let connections = self.lock();
+        self.draining.store(true, Ordering::SeqCst);
+        for tracked in connections.values().filter(|tracked| !tracked.busy) {
+            // closing the read side only, a response may still be on its way
+            let _ = tracked.stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    /// Closes every connection still open and refuses new ones.
+    pub(crate) fn cut_all(&self) {
+        let connections = self.lock();
+        self.cut.store(true, Ordering::SeqCst);
+        for tracked in connections.values() {
+            if tracked.busy {
+                self.cut_off.fetch_add(1, Ordering::SeqCst);
+            }
+            let _ = tracked.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Number of connections closed mid-request by [`ConnectionTracker::cut_all`].
+    pub(crate) fn cut_off(&self) -> usize {
+        self.cut_off.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn count_error(&self) {
+        self.errors.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Number of connections that failed with an I/O error.
+    pub(crate) fn errors(&self) -> u64 {
+        self.errors.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn count_response(&self) {
+        self.responses.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Number of responses written in full, over every connection.
+    pub(crate) fn responses(&self) -> usize {
+        self.responses.load(Ordering::SeqCst)
+    }
+}
+
+/// A tracked connection. Stops being tracked when dropped.
+pub(crate) struct Connection<'a> {
+    tracker: &'a ConnectionTracker,
+    id: u64,
+}
+
+impl Connection<'_> {
+    pub(crate) fn set_busy(&self) {
+        if let Some(tracked) = self.tracker.lock().get_mut(&self.id) {
+            tracked.busy = true;
+        }
+    }
+
+    /// Marks the connection as waiting for the next request. Returns `false` if the server is
+    /// draining, in which case the connection must be closed.
+    pub(crate) fn set_idle(&self) -> bool {
+        let mut connections = self.tracker.lock();
+        if self.tracker.is_draining() {
+            return false;
+        }
+        if let Some(tracked) = connections.get_mut(&self.id) {
+            tracked.busy = false;
+        }
+        true
+    }
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        self.tracker.lock().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (server, client)
+    }
+
+    #[test]
+    fn handle_is_shared_between_clones() {
+        let handle = ShutdownHandle::new();
+        let clone = handle.clone();
+        assert!(!handle.is_requested());
+        clone.shutdown();
+        assert!(handle.is_requested());
+    }
+
+    #[test]
+    fn tracker_closes_idle_connections_when_draining() {
+        let tracker = ConnectionTracker::default();
+        let (idle_stream, _idle_client) = connected_pair();
+        let (busy_stream, _busy_client) = connected_pair();
+        let idle = tracker.register(&idle_stream).unwrap();
+        let busy = tracker.register(&busy_stream).unwrap();
+        assert!(idle.set_idle());
+
+        tracker.start_draining();
+        let mut buffer = [0; 1];
+        assert_eq!(0, (&idle_stream).read(&mut buffer).unwrap());
+        assert!(!busy.set_idle());
+
+        tracker.cut_all();
+        assert_eq!(1, tracker.cut_off());
+        assert_eq!(0, (&busy_stream).read(&mut buffer).unwrap_or(0));
+        assert!(tracker.register(&busy_stream).is_none());
+        assert_eq!(2, tracker.cut_off());
+    }
+}
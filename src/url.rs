@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Decodes the percent-encoded bytes of `input` as UTF-8 (RFC 3986). With `plus_as_space`, `+`
+/// is decoded as a space, as in query strings.
+///
+/// Returns `None` if an escape is malformed, if the bytes are not valid UTF-8 (including overlong
+/// encodings such as `%C0%AE`) or if they contain a NUL byte.
+///
+/// # Examples
+/// ```
+/// use qst::url::percent_decode;
+/// assert_eq!(Some(String::from("café #1.js")), percent_decode("caf%C3%A9%20%231.js", false));
+/// assert_eq!(Some(String::from("a+b")), percent_decode("a+b", false));
+/// assert_eq!(Some(String::from("a b")), percent_decode("a+b", true));
+/// assert_eq!(None, percent_decode("%C0%AE%C0%AE", false));
+/// ```
+pub fn percent_decode(input: &str, plus_as_space: bool) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let high = hex_value(*bytes.get(i + 1)?)?;
+                let low = hex_value(*bytes.get(i + 2)?)?;
+                decoded.push(high << 4 | low);
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    if decoded.contains(&0) {
+        return None;
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Percent-encodes everything but the unreserved characters of RFC 3986, so the result can be
+/// used as a single path segment.
+pub fn percent_encode_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Parses an `application/x-www-form-urlencoded` query string. Keys without a value map to an
+/// empty string and the first occurrence of a repeated key wins. Returns `None` if a key or value
+/// can't be decoded.
+///
+/// # Examples
+/// ```
+/// use qst::url::parse_query;
+/// let query = parse_query("v=3&debug&q=hello+world%21").unwrap();
+/// assert_eq!(Some(&String::from("3")), query.get("v"));
+/// assert_eq!(Some(&String::new()), query.get("debug"));
+/// assert_eq!(Some(&String::from("hello world!")), query.get("q"));
+/// ```
+pub fn parse_query(query: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let key = percent_decode(key, true)?;
+        let value = percent_decode(value, true)?;
+        params.entry(key).or_insert(value);
+    }
+    Some(params)
+}
+
+/// Splits a request target into its path and its query, dropping the fragment.
+///
+/// # Examples
+/// ```
+/// use qst::url::split_target;
+/// assert_eq!(("/app.js", Some("v=3")), split_target("/app.js?v=3#top"));
+/// assert_eq!(("/", None), split_target("/#top"));
+/// ```
+pub fn split_target(target: &str) -> (&str, Option<&str>) {
+    let target = match target.split_once('#') {
+        Some((target, _)) => target,
+        None => target,
+    };
+    match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoding_rejects_invalid_sequences() {
+        assert_eq!(None, percent_decode("%", false));
+        assert_eq!(None, percent_decode("%2", false));
+        assert_eq!(None, percent_decode("%G0", false));
+        assert_eq!(None, percent_decode("%00", false));
+        // lone continuation byte, truncated sequence and surrogate
+        assert_eq!(None, percent_decode("%80", false));
+        assert_eq!(None, percent_decode("%C3", false));
+        assert_eq!(None, percent_decode("%ED%A0%80", false));
+        // overlong encodings of '/' and '.'
+        assert_eq!(None, percent_decode("%C0%AF", false));
+        assert_eq!(None, percent_decode("%E0%80%AE", false));
+    }
+
+    #[test]
+    fn decoding_keeps_plain_text() {
+        assert_eq!(
+            Some(String::from("/a/b.c-d_e~f")),
+            percent_decode("/a/b.c-d_e~f", false)
+        );
+        assert_eq!(
+            Some(String::from("../")),
+            percent_decode("%2e%2E%2f", false)
+        );
+        assert_eq!(Some(String::from("日本")), percent_decode("日本", false));
+    }
+
+    #[test]
+    fn encoding_roundtrips() {
+        for segment in ["a b#c?d", "café", "100%", "a/b", "plain.js"] {
+            let encoded = percent_encode_segment(segment);
+            assert!(!encoded.contains(['/', ' ', '#', '?']));
+            assert_eq!(Some(String::from(segment)), percent_decode(&encoded, false));
+        }
+    }
+
+    #[test]
+    fn query_parsing_rejects_invalid_escapes() {
+        assert_eq!(None, parse_query("a=%zz"));
+        assert_eq!(Some(HashMap::new()), parse_query(""));
+        let query = parse_query("a=1&a=2&&b=").unwrap();
+        assert_eq!(2, query.len());
+        assert_eq!("1", query["a"]);
+        assert_eq!("", query["b"]);
+    }
+}
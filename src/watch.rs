@@ -0,0 +1,476 @@
+use std::collections::BTreeMap;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Names skipped by default: dependencies, version control and the files the server hides.
+pub const DEFAULT_IGNORES: [&str; 3] = ["node_modules", ".git", "_*"];
+
+/// How often the tree is scanned by default.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(300);
+
+/// How long the tree must stay unchanged by default before its changes are reported.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// A change of the watched tree.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Event {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Removed(PathBuf),
+    /// A file moved, keeping its content.
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
+}
+
+impl Event {
+    /// The paths the event concerns: both ends of a rename, the file otherwise.
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            Event::Created(path) | Event::Modified(path) | Event::Removed(path) => vec![path],
+            Event::Renamed { from, to } => vec![from, to],
+        }
+    }
+}
+
+/// What is known of a file to tell whether it changed.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct FileState {
+    len: u64,
+    modified: SystemTime,
+    /// Identifies the file on the device, where the platform tells it.
+    id: Option<(u64, u64)>,
+}
+
+impl FileState {
+    fn of(metadata: &Metadata) -> Option<FileState> {
+        Some(FileState {
+            len: metadata.len(),
+            modified: metadata.modified().ok()?,
+            id: file_id(metadata),
+        })
+    }
+
+    /// Whether `other` may be this file, moved elsewhere. Moving keeps the size and time, which
+    /// also tells apart a new file that got the identity of a removed one.
+    fn same_file(&self, other: &FileState) -> bool {
+        let same_id = match (self.id, other.id) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        same_id && self.len == other.len && self.modified == other.modified
+    }
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// The state of every file below a directory at some point.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Snapshot {
+    files: BTreeMap<PathBuf, FileState>,
+}
+
+impl Snapshot {
+    /// Whether the snapshot holds the file at `path`.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    /// Number of files in the snapshot.
+    pub fn count(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Lists the changes from this snapshot to `newer`: renames, then creations, modifications and
+    /// removals, each sorted by path. A file removed and one created with the same size, time and
+    /// identity, where the platform has identities, make a rename.
+    pub fn changes(&self, newer: &Snapshot) -> Vec<Event> {
+        let mut removed: Vec<(&PathBuf, &FileState)> = self
+            .files
+            .iter()
+            .filter(|(path, _)| !newer.files.contains_key(*path))
+            .collect();
+        let mut created = Vec::new();
+        let mut modified = Vec::new();
+        for (path, state) in &newer.files {
+            match self.files.get(path) {
+                None => created.push((path, state)),
+                Some(old) if old != state => modified.push(Event::Modified(path.clone())),
+                Some(_) => (),
+            }
+        }
+
+        let mut events = Vec::new();
+        created.retain(|(to, state)| {
+            match removed.iter().position(|(_, old)| old.same_file(state)) {
+                Some(index) => {
+                    let (from, _) = removed.remove(index);
+                    events.push(Event::Renamed {
+                        from: from.clone(),
+                        to: to.to_path_buf(),
+                    });
+                    false
+                }
+                None => true,
+            }
+        });
+        events.extend(
+            created
+                .into_iter()
+                .map(|(path, _)| Event::Created(path.clone())),
+        );
+        events.extend(modified);
+        events.extend(
+            removed
+                .into_iter()
+                .map(|(path, _)| Event::Removed(path.clone())),
+        );
+        events
+    }
+}
+
+/// Matches `name` against a glob `pattern`, where `*` stands for any characters but `/` and `?`
+/// for a single one.
+///
+/// # Examples
+/// ```
+/// use qst::watch::glob_match;
+/// assert!(glob_match("_*", "_drafts"));
+/// assert!(glob_match("*.tmp", "build.tmp"));
+/// assert!(glob_match("dist/*.map", "dist/app.js.map"));
+/// assert!(!glob_match("*.map", "dist/app.js.map"));
+/// ```
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // the position after the last `*` and the character it was matched up to, to backtrack to
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some('?') if name[n] != '/' => {
+                p += 1;
+                n += 1;
+            }
+            Some(&c) if c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((after, matched)) if name[matched] != '/' => {
+                    star = Some((after, matched + 1));
+                    p = after;
+                    n = matched + 1;
+                }
+                _ => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Watches a directory tree for changes by scanning it on a regular basis, with nothing but the
+/// standard library. Bursts of writes, as bundlers make, are reported together once the tree has
+/// been quiet for [`Watcher::debounce`].
+///
+/// # Examples
+/// ```
+/// use qst::watch::Watcher;
+/// use std::sync::mpsc;
+///
+/// let mut watcher = Watcher::new("src");
+/// watcher.ignores.push(String::from("*.swp"));
+/// let (sender, receiver) = mpsc::channel();
+/// let handle = watcher.spawn(move |events| {
+///     let _ = sender.send(events);
+/// });
+/// // receiver.recv() blocks until something changes
+/// handle.stop();
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub struct Watcher {
+    /// Directory watched, recursively.
+    pub root: PathBuf,
+    /// Entries skipped, with everything below them. Patterns without `/` match the name of any
+    /// entry, the others its path relative to the root. See [`glob_match`].
+    pub ignores: Vec<String>,
+    /// Time between two scans.
+    pub interval: Duration,
+    /// Time the tree must stay unchanged before its changes are reported.
+    pub debounce: Duration,
+}
+
+impl Watcher {
+    /// Creates a watcher of `root`, ignoring [`DEFAULT_IGNORES`].
+    pub fn new(root: impl Into<PathBuf>) -> Watcher {
+        Watcher {
+            root: root.into(),
+            ignores: DEFAULT_IGNORES
+                .iter()
+                .map(|ignore| ignore.to_string())
+                .collect(),
+            interval: DEFAULT_INTERVAL,
+            debounce: DEFAULT_DEBOUNCE,
+        }
+    }
+
+    /// Whether the entry at `relative`, below the root, is ignored.
+    fn is_ignored(&self, relative: &Path) -> bool {
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        let name = relative.rsplit('/').next().unwrap_or("");
+        self.ignores.iter().any(|pattern| {
+            if pattern.contains('/') {
+                glob_match(pattern.trim_matches('/'), &relative)
+            } else {
+                glob_match(pattern, name)
+            }
+        })
+    }
+
+    /// Whether the changes below `path` are reported: it is the root or below it, and none of the
+    /// entries leading to it is ignored. Paths are compared as they are, not resolved.
+    pub fn covers(&self, path: &Path) -> bool {
+        let relative = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative,
+            Err(_) => return false,
+        };
+        let mut entry = PathBuf::new();
+        relative.iter().all(|name| {
+            entry.push(name);
+            !self.is_ignored(&entry)
+        })
+    }
+
+    fn scan_dir(&self, dir: &Path, snapshot: &mut Snapshot) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let relative = path.strip_prefix(&self.root).unwrap_or(&path);
+            if self.is_ignored(relative) {
+                continue;
+            }
+            match entry.file_type() {
+                // links to directories are not followed, they could loop
+                Ok(file_type) if file_type.is_dir() => self.scan_dir(&path, snapshot),
+                Ok(_) => {
+                    if let Some(state) = fs::metadata(&path).ok().as_ref().and_then(FileState::of) {
+                        snapshot.files.insert(path, state);
+                    }
+                }
+                Err(_) => (),
+            }
+        }
+    }
+
+    /// Records the state of every file below the root, ignored ones aside. Entries that can't be
+    /// read are left out.
+    pub fn scan(&self) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        self.scan_dir(&self.root, &mut snapshot);
+        snapshot
+    }
+
+    /// Watches the tree on a new thread, calling `on_events` with the changes after each burst.
+    /// Watching lasts until the returned handle is stopped or dropped.
+    pub fn spawn<F>(self, mut on_events: F) -> WatchHandle
+    where
+        F: FnMut(Vec<Event>) + Send + 'static,
+    {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&stopped);
+        // changes are reported from the state of the tree when watching starts
+        let mut reported = self.scan();
+        let thread = thread::spawn(move || {
+            // the last state seen, with when it was first seen
+            let mut seen = reported.clone();
+            let mut seen_at = Instant::now();
+            while !stop.load(Ordering::SeqCst) {
+                thread::sleep(self.interval);
+                let current = self.scan();
+                if current != seen {
+                    seen = current;
+                    seen_at = Instant::now();
+                } else if seen != reported && seen_at.elapsed() >= self.debounce {
+                    let events = reported.changes(&seen);
+                    reported = seen.clone();
+                    if !events.is_empty() {
+                        on_events(events);
+                    }
+                }
+            }
+        });
+        WatchHandle {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// Stops a watcher when asked to, or when dropped.
+#[derive(Debug)]
+pub struct WatchHandle {
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WatchHandle {
+    /// Stops watching and waits for the watcher thread, which may take a scan interval.
+    pub fn stop(mut self) {
+        self.join();
+    }
+
+    fn join(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn base(name: &str) -> PathBuf {
+        let base = Path::new("target/qst-tests/watch").join(name);
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).unwrap();
+        base
+    }
+
+    #[test]
+    fn globs_match_names_and_paths() {
+        assert!(glob_match("node_modules", "node_modules"));
+        assert!(!glob_match("node_modules", "node_modules2"));
+        assert!(glob_match("*", ".git"));
+        assert!(glob_match("a*b*c", "aXXbYYbc"));
+        assert!(glob_match("?.js", "a.js"));
+        assert!(!glob_match("?.js", "ab.js"));
+        assert!(!glob_match("a*", "a/b"));
+        assert!(glob_match("a/*/c", "a/b/c"));
+    }
+
+    #[test]
+    fn scans_skip_ignored_entries() {
+        let root = base("ignores");
+        for dir in [
+            "node_modules/pkg",
+            ".git",
+            "_drafts",
+            "src/gen",
+            "src/_partials",
+        ] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "index.html",
+            "node_modules/pkg/index.js",
+            ".git/HEAD",
+            "_drafts/post.md",
+            "src/app.js",
+            "src/_partials/nav.html",
+            "src/gen/out.js",
+            "src/a.swp",
+        ] {
+            fs::write(root.join(file), file).unwrap();
+        }
+        let mut watcher = Watcher::new(&root);
+        watcher.ignores.push(String::from("*.swp"));
+        watcher.ignores.push(String::from("/src/gen/"));
+        let snapshot = watcher.scan();
+        assert_eq!(2, snapshot.count());
+        assert!(snapshot.contains(&root.join("index.html")));
+        assert!(snapshot.contains(&root.join("src/app.js")));
+    }
+
+    #[test]
+    fn watchers_cover_the_entries_they_scan() {
+        let watcher = Watcher::new("dist");
+        assert!(watcher.covers(Path::new("dist")));
+        assert!(watcher.covers(Path::new("dist/assets/img")));
+        assert!(!watcher.covers(Path::new("dist/node_modules/pkg")));
+        assert!(!watcher.covers(Path::new("dist/assets/_drafts")));
+        assert!(!watcher.covers(Path::new("distant")));
+        assert!(!watcher.covers(Path::new("src")));
+    }
+
+    #[test]
+    fn changes_are_typed() {
+        let root = base("changes");
+        fs::write(root.join("kept.js"), "kept").unwrap();
+        fs::write(root.join("edited.js"), "edited").unwrap();
+        fs::write(root.join("gone.js"), "gone").unwrap();
+        fs::write(root.join("old.js"), "old").unwrap();
+        let watcher = Watcher::new(&root);
+        let before = watcher.scan();
+
+        fs::write(root.join("edited.js"), "edited again").unwrap();
+        fs::remove_file(root.join("gone.js")).unwrap();
+        fs::rename(root.join("old.js"), root.join("new.js")).unwrap();
+        fs::write(root.join("added.js"), "added").unwrap();
+        assert_eq!(
+            vec![
+                Event::Renamed {
+                    from: root.join("old.js"),
+                    to: root.join("new.js"),
+                },
+                Event::Created(root.join("added.js")),
+                Event::Modified(root.join("edited.js")),
+                Event::Removed(root.join("gone.js")),
+            ],
+            before.changes(&watcher.scan())
+        );
+    }
+
+    #[test]
+    fn bursts_are_reported_together() {
+        let root = base("bursts");
+        let mut watcher = Watcher::new(&root);
+        watcher.interval = Duration::from_millis(10);
+        watcher.debounce = Duration::from_millis(200);
+        let (sender, receiver) = mpsc::channel();
+        let handle = watcher.spawn(move |events| sender.send(events).unwrap());
+
+        // a burst of writes, each within the debounce delay of the previous one
+        for i in 0..5 {
+            fs::write(root.join("bundle.js"), "x".repeat(i + 1)).unwrap();
+            thread::sleep(Duration::from_millis(30));
+        }
+        let events = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(vec![Event::Created(root.join("bundle.js"))], events);
+        assert!(receiver.recv_timeout(Duration::from_millis(400)).is_err());
+
+        fs::remove_file(root.join("bundle.js")).unwrap();
+        let events = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(vec![Event::Removed(root.join("bundle.js"))], events);
+        handle.stop();
+    }
+}